@@ -0,0 +1,70 @@
+use crate::metadata;
+use crate::scanner::Scanner;
+use std::collections::HashSet;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// Identical files that would lose their own owners or permissions if they were linked, from `Scanner::access_conflicts()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessConflict {
+    /// One path for every separate file (not its hardlinks)
+    pub files: Vec<FileAccess>,
+    pub owner: bool,
+    pub group: bool,
+    /// Permission bits
+    pub mode: bool,
+    /// `user.` and `security.` extended attributes, which include SELinux contexts
+    pub xattrs: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileAccess {
+    pub path: Box<Path>,
+    pub uid: u32,
+    pub gid: u32,
+    /// Permission bits, without the file type
+    pub mode: u32,
+}
+
+impl Scanner {
+    /// Groups of duplicates whose files have different owners, groups, permissions, or extended attributes.
+    /// Linking makes them all share one set of these, so it's meant for dry runs, before anything has been linked.
+    pub fn access_conflicts(&self) -> Vec<AccessConflict> {
+        let mut conflicts: Vec<_> = self.dupes_iter().filter_map(|sets| {
+            // Dry runs put all paths in one set as if they were linked, so inodes are checked again
+            let mut inodes = HashSet::new();
+            let files: Vec<_> = sets.into_iter()
+                .flat_map(|set| set.paths)
+                .filter_map(|path| {
+                    let m = fs::symlink_metadata(&path).ok()?;
+                    if !inodes.insert((m.dev(), m.ino())) {
+                        return None;
+                    }
+                    let xattrs = metadata::xattrs(&path).ok();
+                    Some((FileAccess { path, uid: m.uid(), gid: m.gid(), mode: m.mode() & 0o7777 }, xattrs))
+                })
+                .collect();
+            if files.len() < 2 {
+                return None;
+            }
+            let conflict = AccessConflict {
+                owner: differ(files.iter().map(|(f, _)| f.uid)),
+                group: differ(files.iter().map(|(f, _)| f.gid)),
+                mode: differ(files.iter().map(|(f, _)| f.mode)),
+                xattrs: differ(files.iter().map(|(_, xattrs)| xattrs)),
+                files: files.into_iter().map(|(f, _)| f).collect(),
+            };
+            (conflict.owner || conflict.group || conflict.mode || conflict.xattrs).then_some(conflict)
+        }).collect();
+        conflicts.sort_unstable_by(|a, b| a.files[0].path.cmp(&b.files[0].path));
+        conflicts
+    }
+}
+
+fn differ<T: PartialEq>(mut values: impl Iterator<Item = T>) -> bool {
+    match values.next() {
+        Some(first) => values.any(|v| v != first),
+        None => false,
+    }
+}
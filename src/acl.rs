@@ -0,0 +1,116 @@
+use std::io;
+use std::path::Path;
+
+const VERSION: u32 = 2;
+const USER_OBJ: u16 = 0x01;
+const USER: u16 = 0x02;
+const GROUP_OBJ: u16 = 0x04;
+const GROUP: u16 = 0x08;
+const MASK: u16 = 0x10;
+const OTHER: u16 = 0x20;
+const READ: u16 = 4;
+/// Id of entries that aren't for a specific user or group
+const NO_ID: u32 = u32::MAX;
+
+/// Field order is the order the kernel requires
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Entry {
+    tag: u16,
+    id: u32,
+    perm: u16,
+}
+
+/// Adds a POSIX ACL entry allowing the user to read the file. Permissions of everyone else stay the same.
+#[cfg(target_os = "linux")]
+pub(crate) fn grant_read(path: &Path, uid: u32) -> io::Result<()> {
+    use crate::metadata::read_sized;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+
+    const NAME: &[u8] = b"system.posix_acl_access\0";
+    let path_c = CString::new(path.as_os_str().as_bytes())?;
+    let existing = match read_sized(|buf, len| unsafe { libc::lgetxattr(path_c.as_ptr(), NAME.as_ptr().cast(), buf.cast(), len) }) {
+        Ok(data) => data,
+        Err(err) if err.raw_os_error() == Some(libc::ENODATA) => Vec::new(),
+        Err(err) => return Err(err),
+    };
+    let mut entries = if existing.is_empty() {
+        from_mode(std::fs::symlink_metadata(path)?.mode())
+    } else {
+        parse(&existing).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unsupported ACL format"))?
+    };
+    add_user_read(&mut entries, uid);
+    let data = serialize(&entries);
+    if unsafe { libc::lsetxattr(path_c.as_ptr(), NAME.as_ptr().cast(), data.as_ptr().cast(), data.len(), 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn grant_read(_: &Path, _: u32) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "ACLs are only supported on Linux"))
+}
+
+/// The minimal ACL equivalent to the permission bits
+fn from_mode(mode: u32) -> Vec<Entry> {
+    let perm = |shift: u32| ((mode >> shift) & 7) as u16;
+    vec![
+        Entry { tag: USER_OBJ, id: NO_ID, perm: perm(6) },
+        Entry { tag: GROUP_OBJ, id: NO_ID, perm: perm(3) },
+        Entry { tag: OTHER, id: NO_ID, perm: perm(0) },
+    ]
+}
+
+fn add_user_read(entries: &mut Vec<Entry>, uid: u32) {
+    match entries.iter_mut().find(|e| e.tag == USER && e.id == uid) {
+        Some(e) => e.perm |= READ,
+        None => entries.push(Entry { tag: USER, id: uid, perm: READ }),
+    }
+    // The mask limits named entries, and is required when there are any
+    match entries.iter_mut().find(|e| e.tag == MASK) {
+        Some(e) => e.perm |= READ,
+        None => {
+            let perm = entries.iter().filter(|e| matches!(e.tag, USER | GROUP_OBJ | GROUP)).fold(0, |perm, e| perm | e.perm);
+            entries.push(Entry { tag: MASK, id: NO_ID, perm });
+        },
+    }
+    entries.sort();
+}
+
+fn parse(data: &[u8]) -> Option<Vec<Entry>> {
+    let (version, entries) = data.split_at(4.min(data.len()));
+    if version != VERSION.to_le_bytes() || entries.len() % 8 != 0 {
+        return None;
+    }
+    Some(entries.chunks_exact(8).map(|e| Entry {
+        tag: u16::from_le_bytes([e[0], e[1]]),
+        perm: u16::from_le_bytes([e[2], e[3]]),
+        id: u32::from_le_bytes([e[4], e[5], e[6], e[7]]),
+    }).collect())
+}
+
+fn serialize(entries: &[Entry]) -> Vec<u8> {
+    let mut data = VERSION.to_le_bytes().to_vec();
+    for e in entries {
+        data.extend_from_slice(&e.tag.to_le_bytes());
+        data.extend_from_slice(&e.perm.to_le_bytes());
+        data.extend_from_slice(&e.id.to_le_bytes());
+    }
+    data
+}
+
+#[test]
+fn add_user_read_test() {
+    let mut entries = from_mode(0o640);
+    add_user_read(&mut entries, 1234);
+    assert_eq!(vec![
+        Entry { tag: USER_OBJ, id: NO_ID, perm: 6 },
+        Entry { tag: USER, id: 1234, perm: READ },
+        Entry { tag: GROUP_OBJ, id: NO_ID, perm: 4 },
+        Entry { tag: MASK, id: NO_ID, perm: 4 },
+        Entry { tag: OTHER, id: NO_ID, perm: 0 },
+    ], entries);
+    assert_eq!(Some(entries.clone()), parse(&serialize(&entries)));
+}
@@ -1,48 +1,261 @@
 use dupe_krill::Scanner;
 use dupe_krill::*;
 use getopts::Options;
+use std::cell::Cell;
 use std::env;
+use std::ffi::CString;
+use std::fs;
 use std::io;
 use std::io::Write;
-use std::path::PathBuf;
-use std::sync::atomic::AtomicU32;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicU32};
 use std::sync::atomic::Ordering;
+use std::time::Duration;
 
 enum OutputMode {
     Quiet,
+    Summary,
     Text,
     Json,
+    Fdupes,
+    Rmlint,
+    Script,
 }
+enum Command {
+    Scan,
+    Compare,
+    Apply,
+    Verify,
+    Undo,
+    Stats,
+    Merge,
+}
+
+impl Command {
+    /// Subcommand given as the first argument. Without one, the arguments are paths to scan.
+    fn from_arg(arg: &str) -> Option<Self> {
+        Some(match arg {
+            "scan" => Command::Scan,
+            "compare" => Command::Compare,
+            "apply" => Command::Apply,
+            "verify" => Command::Verify,
+            "undo" => Command::Undo,
+            "stats" => Command::Stats,
+            "merge" => Command::Merge,
+            _ => return None,
+        })
+    }
+}
+
+/// With `--fail-on-dupes`, or when `verify` finds groups that have diverged
+const EXIT_DUPES_FOUND: i32 = 1;
+/// Finished, but some files couldn't be read or changed
+const EXIT_FILE_ERRORS: i32 = 2;
+/// Invalid arguments, or an error that stopped the run
+const EXIT_FATAL: i32 = 3;
+
+/// What the exit code depends on, collected by `ExitStatusListener`
+#[derive(Debug, Default)]
+struct ExitStatus {
+    errors: Cell<usize>,
+    dupes: Cell<usize>,
+}
+
+#[derive(Debug)]
+struct ExitStatusListener(Rc<ExitStatus>);
+
+impl ScanListener for ExitStatusListener {
+    fn file_scanned(&mut self, _: &Path, _: &Stats) {}
+
+    fn scan_over(&self, _: &Scanner, stats: &Stats, _: Duration) {
+        self.0.dupes.set(self.0.dupes.get() + stats.dupes);
+    }
+
+    fn hardlinked(&mut self, _: &Path, _: &Path) {}
+
+    fn duplicate_found(&mut self, _: &Path, _: &Path) {}
+
+    fn error(&mut self, _: &Path, _: &io::Error) {
+        self.0.errors.set(self.0.errors.get() + 1);
+    }
+}
+
 static CTRL_C_BREAKS: AtomicU32 = AtomicU32::new(0);
+static STATUS_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigusr1(_: libc::c_int) {
+    // Only an atomic store is safe in a signal handler. The scanner prints when it sees it.
+    STATUS_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn on_sigterm(_: libc::c_int) {
+    CTRL_C_BREAKS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// For `--daemon-friendly` without `--lock`. systemd sets `RUNTIME_DIRECTORY` for services with `RuntimeDirectory=`.
+fn default_lock_path() -> PathBuf {
+    env::var_os("RUNTIME_DIRECTORY").or_else(|| env::var_os("XDG_RUNTIME_DIR"))
+        .map_or_else(env::temp_dir, PathBuf::from)
+        .join("dupe-krill.lock")
+}
 
 fn main() {
+    let mut args = env::args();
+    let program = args.next();
+    let program = program.as_deref().unwrap_or(env!("CARGO_PKG_NAME"));
+    let mut args: Vec<_> = args.collect();
+    let command = args.first().and_then(|arg| Command::from_arg(arg));
+    if command.is_some() {
+        args.remove(0);
+    }
+    match command.unwrap_or(Command::Scan) {
+        Command::Verify => verify_main(program, args),
+        Command::Undo => undo_main(program, args),
+        Command::Stats => stats_main(program, args),
+        Command::Merge => merge_main(program, args),
+        command => scan_main(program, command, args),
+    }
+}
+
+/// Usage of all subcommands, shown in their help
+fn commands_usage(program: &str) -> String {
+    format!("Usage: {p} [scan] [options] <files or directories>\n       {p} compare [options] <dir> <dir to check>\n       {p} apply [options] <report.json>\n       {p} verify <report.json>\n       {p} undo <undo log>\n       {p} stats [options] <report.json>\n       {p} merge [options] <report.json>...\n\nSee {p} <command> -h for options of each command.", p = program)
+}
+
+fn parse_args_or_exit(opts: &Options, args: Vec<String>) -> getopts::Matches {
+    opts.parse(args).unwrap_or_else(|err| {
+        writeln!(&mut std::io::stderr(), "Error: {}", err).unwrap();
+        std::process::exit(EXIT_FATAL);
+    })
+}
+
+/// `scan`, `compare`, and `apply` share all the options for comparing and linking files
+fn scan_main(program: &str, command: Command, args: Vec<String>) {
     let mut opts = Options::new();
     opts.optflag("d", "dry-run", "Do not change anything on disk. Only print dupes found");
+    opts.optflag("", "trash", "Move duplicates to the trash instead of hardlinking them, keeping one copy");
+    opts.optopt("", "quarantine", "Move duplicates to this directory (on the same filesystem) instead of hardlinking them, under their paths relative to the scanned directory", "<dir>");
     opts.optflag("s", "small", "Also dedupe small files (smaller than a disk block)");
+    opts.optflag("", "snapshots", "Also scan snapshot directories (.snapshot, .zfs, read-only Btrfs subvolumes, Time Machine backups)");
+    opts.optflag("", "empty", "Also dedupe empty files");
+    opts.optflag("", "noatime", "Don't change access times of files that are read (Linux only, for files owned by the user)");
+    opts.optflag("", "no-hdd-tuning", "Read files on hard disks the same way as on SSDs (by default they're read in larger parts with read-ahead, to seek less)");
+    opts.optflag("", "prefetch", "Read ahead the next part of files while comparing, which helps keep hard disks streaming");
+    opts.optflag("", "drop-cache", "Remove files from the OS cache after reading them, to keep other programs' data cached");
+    opts.optflag("", "nice", "Run with the lowest CPU and disk priority, so that other programs aren't slowed down (Linux and macOS)");
     opts.optflag("q", "quiet", "Hide regular progress output");
+    opts.optflag("", "summary-only", "Print only the final summary line");
+    opts.optflag("", "isolate", "Only link files with files from other directories given on the command line, not within the same directory (e.g. to dedupe backup snapshots against each other)");
+    opts.optflag("", "dirs", "Also report directories with identical contents (works best with --small)");
+    opts.optflag("", "access-conflicts", "Also list duplicates that have different owners, permissions, or extended attributes, which linking would make the same (use with -d)");
+    opts.optflag("", "hardlinks", "Also list groups of files that are hardlinks of each other");
+    opts.optflag("", "count-first", "Count files before scanning to show percentage done and time left");
+    opts.optopt("", "older-than", "Only dedupe files last modified longer ago than this (e.g. 90d, 12h, 30m)", "<duration>");
+    opts.optopt("", "min-age", "Same as --older-than. Files still being written by running jobs aren't replaced", "<duration>");
+    opts.optopt("", "newer-than", "Only dedupe files last modified more recently than this", "<duration>");
+    opts.optopt("", "owner", "Only dedupe files owned by this user (name or uid)", "<user>");
+    opts.optopt("", "group", "Only dedupe files belonging to this group (name or gid)", "<group>");
+    opts.optopt("", "retries", "Retry linking this many times after temporary errors, e.g. on network filesystems (default 3)", "<number>");
+    opts.optopt("", "max-runtime", "Stop scanning after this time (e.g. 2h), and only link dupes found so far", "<duration>");
+    opts.optopt("", "stop-after-saving", "Stop scanning once this much space can be saved (e.g. 500M, 10G)", "<size>");
+    opts.optopt("", "stop-after-groups", "Stop scanning once this many groups of duplicates have been found", "<number>");
+    opts.optopt("", "chunk-size", "Size of the first part of files to compare (default 2K). Later parts grow until they reach --max-chunk-size", "<size>");
+    opts.optopt("", "chunk-growth", "How many times bigger every next part of compared files is (default 16)", "<number>");
+    opts.optopt("", "max-chunk-size", "Largest part of a file read at once (default 128M). Lower it on devices with little memory", "<size>");
+    opts.optopt("", "whole-file-size", "Read files up to this size at once instead of in parts (default 256K, 0 to disable)", "<size>");
+    opts.optflag("", "grant-access", "When replacing a file owned by another user, give that user read access to the kept file with an ACL entry (Linux only)");
+    opts.optflag("", "ignore-selinux", "Link files even if their SELinux contexts differ (only one of them is kept)");
+    opts.optflag("", "require-same-xattrs", "Don't link files that have different user or security extended attributes (Linux only)");
+    opts.optflag("", "skip-open", "Skip files that other processes have open for writing (Linux only, for files owned by the user)");
+    opts.optopt("", "max-links", "Skip files that already have this many hardlinks", "<number>");
+    opts.optopt("", "keep", "Which copy is kept and linked to: most-links (default, fewest changes) or oldest (keeps the original modification time)", "<policy>");
+    opts.optopt("", "linked-mtime", "Set the modification time of linked files to the oldest or newest of the copies, instead of keeping the one of the kept file", "<oldest|newest>");
+    opts.optmulti("", "prefer-root", "Also scan this directory, and always keep its files when they have duplicates elsewhere, linking the other copies to them", "<dir>");
+    opts.optmulti("", "reference", "Also scan this directory, but never modify files in it. Its files are only used as the originals to link to", "<dir>");
+    opts.optmulti("", "keep-matching", "Keep files matching this pattern when they have duplicates, and link the other copies to them (same syntax as --protect)", "<pattern>");
+    opts.optmulti("", "protect", "Never replace files matching this pattern (relative to the scanned directory, same syntax as --exclude-from)", "<pattern>");
     opts.optmulti("e", "exclude", "Don't scan directories or files with that filename (wildcards are not supported)", "<exact filename>");
+    opts.optflag("", "daemon-friendly", "For running as a systemd service: takes a lock (--lock, or dupe-krill.lock in $RUNTIME_DIRECTORY), sends progress with sd_notify, and stops gracefully on SIGTERM (saving --checkpoint)");
+    opts.optopt("", "lock", "Hold a lock on this file while running, and exit with an error if another run already holds it", "<file>");
+    opts.optopt("", "log", "Append a timestamped record of every link, skipped file, and error to this file", "<file>");
+    opts.optopt("", "undo-log", "Append every replaced file to this log, so that it can be restored with the undo command", "<file>");
+    opts.optopt("", "export-hashes", "Write BLAKE3 hashes of all scanned files to this file (- for stdout), in the format of b3sum, with sizes and modification times in comments", "<file>");
+    opts.optmulti("", "import-hashes", "Read BLAKE3 hashes from a file written by --export-hashes or b3sum. Files with the same size and modification time aren't read until they're linked, and files only listed in it (e.g. from another machine) are reported as duplicates", "<file>");
+    opts.optmulti("", "remote", "Hash files in this directory on another machine over ssh (dupe-krill has to be installed there), and report local files that have a copy there", "<[user@]host:dir>");
+    opts.optmulti("", "remap", "Read paths in --import-hashes files starting with <from> as starting with <to>, e.g. if the files were hashed on another machine where they're mounted elsewhere", "<from>=<to>");
+    opts.optopt("", "checkpoint", "If interrupted with Ctrl-C, save progress to this file. If the file exists, continue the scan from it", "<file>");
+    opts.optflag("", "no-default-excludes", "Also scan version control and cache directories (.git/objects, .hg, .svn, node_modules/.cache, browser caches), and on macOS .DS_Store, ._* files, .Spotlight-V100, and .fseventsd");
+    opts.optmulti("", "exclude-from", "Read exclude patterns from a file, one per line, in the same format as rsync's --exclude-from", "<file>");
+    opts.optopt("", "exec", "Run a shell command for every group of duplicates at the end. Paths are passed as arguments (\"$@\") and on stdin. Combine with -d to only run the command", "<command>");
     opts.optflag("", "json", "Display results as JSON");
+    opts.optopt("", "json-file", "Also write the JSON report to this file, e.g. to keep the text output on the terminal", "<file>");
+    opts.optopt("", "format", "Output format: text (default), json, fdupes (groups separated by blank lines), rmlint (rmlint's JSON format), or sh (script of links to review and run later)", "<format>");
+    opts.optflag("", "bytes", "Print sizes in bytes instead of KiB, MiB, etc.");
+    opts.optopt("", "color", "Colored output: auto (default, only on a terminal), always, or never", "<when>");
+    opts.optflag("", "fail-on-dupes", "Exit with code 1 if any duplicates have been found");
     opts.optflag("h", "help", "This help text");
 
-    let mut args = env::args();
-    let program = args.next();
-    let program = program.as_deref().unwrap_or(env!("CARGO_PKG_NAME"));
+    let matches = parse_args_or_exit(&opts, args);
+    let output_mode = match matches.opt_str("format").as_deref() {
+        Some("json") => OutputMode::Json,
+        Some("fdupes") => OutputMode::Fdupes,
+        Some("rmlint") => OutputMode::Rmlint,
+        Some("sh") => OutputMode::Script,
+        Some("text") | None => if matches.opt_present("json") {
+            OutputMode::Json
+        } else if matches.opt_present("quiet") {
+            OutputMode::Quiet
+        } else if matches.opt_present("summary-only") {
+            OutputMode::Summary
+        } else {
+            OutputMode::Text
+        },
+        Some(other) => {
+            writeln!(&mut std::io::stderr(), "Error: unknown format '{}'", other).unwrap();
+            std::process::exit(EXIT_FATAL);
+        },
+    };
+    // fdupes exits with 1 on any error, and scripts written for it check for that
+    let (exit_fatal, exit_file_errors) = if matches!(output_mode, OutputMode::Fdupes) { (1, 1) } else { (EXIT_FATAL, EXIT_FILE_ERRORS) };
 
-    let matches = opts.parse(args).unwrap();
-    let output_mode = if matches.opt_present("json") {
-        OutputMode::Json
-    } else if matches.opt_present("quiet") {
-        OutputMode::Quiet
-    } else {
-        OutputMode::Text
+    let color = match matches.opt_str("color").as_deref() {
+        Some("auto") | None => ColorMode::Auto,
+        Some("always") => ColorMode::Always,
+        Some("never") => ColorMode::Never,
+        Some(other) => {
+            writeln!(&mut std::io::stderr(), "Error: unknown --color '{}'. Expected auto, always, or never", other).unwrap();
+            std::process::exit(exit_fatal);
+        },
     };
 
-    if matches.opt_present("h") || matches.free.is_empty() {
+    let linked_mtime = match matches.opt_str("linked-mtime").as_deref() {
+        None => LinkedMtime::Unchanged,
+        Some("oldest") => LinkedMtime::Oldest,
+        Some("newest") => LinkedMtime::Newest,
+        Some(other) => {
+            writeln!(&mut std::io::stderr(), "Error: unknown --linked-mtime '{}'. Expected oldest or newest", other).unwrap();
+            std::process::exit(exit_fatal);
+        },
+    };
+
+    let keep = match matches.opt_str("keep").as_deref() {
+        Some("most-links") | None => Keep::MostLinks,
+        Some("oldest") => Keep::Oldest,
+        Some(other) => {
+            writeln!(&mut std::io::stderr(), "Error: unknown --keep '{}'. Expected most-links or oldest", other).unwrap();
+            std::process::exit(exit_fatal);
+        },
+    };
+
+    let free = matches.free.clone();
+
+    if matches.opt_present("h") || (free.is_empty() && !matches.opt_present("reference") && !matches.opt_present("prefer-root")) {
         println!(
             "Hardlink files with duplicate content (v{}).\n{}\n\n{}",
             env!("CARGO_PKG_VERSION"),
             env!("CARGO_PKG_HOMEPAGE"),
-            opts.usage(&(opts.short_usage(program) + " <files or directories>"))
+            opts.usage(&commands_usage(program))
         );
         return;
     }
@@ -52,50 +265,638 @@ fn main() {
     })
     .ok();
 
+    // `kill -USR1 <pid>` prints current status
+    unsafe {
+        libc::signal(libc::SIGUSR1, on_sigusr1 as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+
+    if matches.opt_present("nice") {
+        if let Err(err) = lower_priority() {
+            writeln!(&mut std::io::stderr(), "Warning: can't lower priority: {}", err).unwrap();
+        }
+    }
+
     let mut s = Scanner::new();
     s.settings.break_on = Some(&CTRL_C_BREAKS);
-    s.settings.run_mode = if matches.opt_present("dry-run") { RunMode::DryRun } else { RunMode::Hardlink };
+    s.settings.status_on = Some(&STATUS_REQUESTED);
+    s.settings.run_mode = if matches.opt_present("dry-run") {
+        RunMode::DryRun
+    } else if matches.opt_present("trash") || matches.opt_present("quarantine") {
+        RunMode::Trash
+    } else {
+        RunMode::Hardlink
+    };
+    s.settings.trash_dir = matches.opt_str("quarantine").map(PathBuf::from);
     s.settings.ignore_small = !matches.opt_present("small");
-    match output_mode {
-        OutputMode::Quiet => {
-            // Noop-output is already set by default.
-        },
-        OutputMode::Text => {
-            // TODO this print statement belongs into the TextUserInterface.
-            if s.settings.run_mode == RunMode::DryRun {
-                println!("Dry run. No files will be changed.");
-            }
-            s.set_listener(Box::new(TextUserInterface::new()));
-        },
-        OutputMode::Json => {
-            if s.settings.run_mode == RunMode::DryRun {
-                s.settings.run_mode = RunMode::DryRunNoMerging;
-            }
-            if cfg!(feature = "json") {
-                #[cfg(feature = "json")]
-                s.set_listener(Box::new(JsonOutput::new()))
-            } else {
-                writeln!(&mut std::io::stderr(), "This binary was compiled without JSON support.").unwrap();
-                std::process::exit(2)
-            }
-        },
+    s.settings.include_empty = matches.opt_present("empty");
+    s.settings.scan_snapshots = matches.opt_present("snapshots");
+    s.settings.default_excludes = !matches.opt_present("no-default-excludes");
+    s.settings.read_options.no_atime = matches.opt_present("noatime");
+    s.settings.read_options.drop_cache = matches.opt_present("drop-cache");
+    s.settings.read_options.prefetch = matches.opt_present("prefetch");
+    s.settings.tune_for_hard_disks = !matches.opt_present("no-hdd-tuning");
+    s.settings.count_first = matches.opt_present("count-first");
+    s.settings.isolate_roots = matches.opt_present("isolate");
+    s.settings.find_duplicate_dirs = matches.opt_present("dirs");
+    s.settings.report_hardlinks = matches.opt_present("hardlinks");
+    s.settings.report_access_conflicts = matches.opt_present("access-conflicts");
+    s.settings.skip_open_files = matches.opt_present("skip-open");
+    s.settings.require_same_xattrs = matches.opt_present("require-same-xattrs");
+    s.settings.ignore_selinux = matches.opt_present("ignore-selinux");
+    s.settings.grant_read_access = matches.opt_present("grant-access");
+    s.settings.keep = keep;
+    s.settings.linked_mtime = linked_mtime;
+    s.settings.older_than = duration_opt(&matches, "older-than").or_else(|| duration_opt(&matches, "min-age"));
+    s.settings.newer_than = duration_opt(&matches, "newer-than");
+    s.settings.max_runtime = duration_opt(&matches, "max-runtime");
+    s.settings.stop_after_saving = matches.opt_str("stop-after-saving").map(|arg| parse_size(&arg).unwrap_or_else(|| {
+        writeln!(&mut std::io::stderr(), "Error: invalid --stop-after-saving value '{}'. Expected a number of bytes, optionally with a unit: K, M, G, or T", arg).unwrap();
+        std::process::exit(exit_fatal);
+    }));
+    if let Some(arg) = matches.opt_str("chunk-size") {
+        s.settings.chunk_sizes.initial = chunk_size_or_exit("chunk-size", &arg);
+    }
+    if let Some(arg) = matches.opt_str("chunk-growth") {
+        s.settings.chunk_sizes.growth = arg.parse().ok().filter(|&n| n > 0).unwrap_or_else(|| {
+            writeln!(&mut std::io::stderr(), "Error: invalid --chunk-growth value '{}'", arg).unwrap();
+            std::process::exit(exit_fatal);
+        });
+    }
+    if let Some(arg) = matches.opt_str("max-chunk-size") {
+        s.settings.chunk_sizes.max = chunk_size_or_exit("max-chunk-size", &arg);
+    }
+    if let Some(arg) = matches.opt_str("whole-file-size") {
+        s.settings.chunk_sizes.whole_file = parse_size(&arg).and_then(|size| size.try_into().ok()).unwrap_or_else(|| {
+            writeln!(&mut std::io::stderr(), "Error: invalid --whole-file-size value '{}'", arg).unwrap();
+            std::process::exit(exit_fatal);
+        });
+    }
+    s.settings.max_links = matches.opt_str("max-links").map(|arg| arg.parse().unwrap_or_else(|_| {
+        writeln!(&mut std::io::stderr(), "Error: invalid --max-links value '{}'", arg).unwrap();
+        std::process::exit(exit_fatal);
+    }));
+    if let Some(arg) = matches.opt_str("retries") {
+        s.settings.link_retries = arg.parse().unwrap_or_else(|_| {
+            writeln!(&mut std::io::stderr(), "Error: invalid --retries value '{}'", arg).unwrap();
+            std::process::exit(exit_fatal);
+        });
+    }
+    s.settings.stop_after_groups = matches.opt_str("stop-after-groups").map(|arg| arg.parse().unwrap_or_else(|_| {
+        writeln!(&mut std::io::stderr(), "Error: invalid --stop-after-groups value '{}'", arg).unwrap();
+        std::process::exit(exit_fatal);
+    }));
+    s.settings.owner = matches.opt_str("owner").map(|name| id_or_exit("owner", &name, lookup_uid(&name)));
+    s.settings.group = matches.opt_str("group").map(|name| id_or_exit("group", &name, lookup_gid(&name)));
+    let daemon = matches.opt_present("daemon-friendly");
+    // Taken before anything is changed, and released on exit
+    let lock_path = matches.opt_str("lock").map(PathBuf::from).or_else(|| daemon.then(default_lock_path));
+    let _lock = lock_path.map(|path| LockFile::acquire(&path).unwrap_or_else(|err| {
+        writeln!(&mut std::io::stderr(), "Error: can't lock {}: {}", path.display(), err).unwrap();
+        std::process::exit(exit_fatal);
+    }));
+    if daemon {
+        // Stops like Ctrl-C: links dupes found so far, and saves the checkpoint
+        unsafe {
+            libc::signal(libc::SIGTERM, on_sigterm as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        }
+    }
+
+    let mut listeners = MultiListener::new();
+    if let Command::Compare = command {
+        if free.len() != 2 {
+            writeln!(&mut std::io::stderr(), "Error: compare needs two directories: one to compare with, and one to check for files that aren't in the first one").unwrap();
+            std::process::exit(exit_fatal);
+        }
+        // Nothing is changed, and every file counts when deciding whether a copy is redundant
+        s.settings.run_mode = RunMode::DryRunNoMerging;
+        s.settings.ignore_small = false;
+        let mut compare = CompareOutput::new(&free[0], &free[1]);
+        compare.set_summary_only(matches.opt_present("summary-only") || matches.opt_present("quiet"));
+        compare.set_exact_bytes(matches.opt_present("bytes"));
+        listeners.push(Box::new(compare));
+    } else {
+        match output_mode {
+            OutputMode::Quiet => {
+                // Noop-output is already set by default.
+            },
+            OutputMode::Summary => {
+                let mut ui = TextUserInterface::summary_only();
+                ui.set_color(color);
+                ui.set_exact_bytes(matches.opt_present("bytes"));
+                listeners.push(Box::new(ui));
+            },
+            OutputMode::Text => {
+                // TODO this print statement belongs into the TextUserInterface.
+                if s.settings.run_mode == RunMode::DryRun {
+                    println!("Dry run. No files will be changed.");
+                }
+                let mut ui = TextUserInterface::new();
+                ui.set_color(color);
+                ui.set_exact_bytes(matches.opt_present("bytes"));
+                listeners.push(Box::new(ui));
+            },
+            OutputMode::Json => {
+                if s.settings.run_mode == RunMode::DryRun {
+                    s.settings.run_mode = RunMode::DryRunNoMerging;
+                }
+                if cfg!(feature = "json") {
+                    #[cfg(feature = "json")]
+                    listeners.push(Box::new(JsonOutput::new()))
+                } else {
+                    writeln!(&mut std::io::stderr(), "This binary was compiled without JSON support.").unwrap();
+                    std::process::exit(exit_fatal)
+                }
+            },
+            OutputMode::Fdupes => {
+                if s.settings.run_mode == RunMode::DryRun {
+                    s.settings.run_mode = RunMode::DryRunNoMerging;
+                }
+                listeners.push(Box::new(FdupesOutput::new()));
+            },
+            OutputMode::Rmlint => {
+                if s.settings.run_mode == RunMode::DryRun {
+                    s.settings.run_mode = RunMode::DryRunNoMerging;
+                }
+                if cfg!(feature = "json") {
+                    #[cfg(feature = "json")]
+                    listeners.push(Box::new(RmlintOutput::new()))
+                } else {
+                    writeln!(&mut std::io::stderr(), "This binary was compiled without JSON support.").unwrap();
+                    std::process::exit(exit_fatal)
+                }
+            },
+            OutputMode::Script => {
+                // The script is instead of changing files
+                s.settings.run_mode = RunMode::DryRun;
+                listeners.push(Box::new(ShellScriptOutput::new()));
+            },
+        }
+    }
+    if matches.opt_present("json-file") {
+        if cfg!(feature = "json") {
+            #[cfg(feature = "json")]
+            listeners.push(Box::new(JsonOutput::to_file(matches.opt_str("json-file").unwrap())))
+        } else {
+            writeln!(&mut std::io::stderr(), "This binary was compiled without JSON support.").unwrap();
+            std::process::exit(exit_fatal)
+        }
+    }
+    if let Some(path) = matches.opt_str("log") {
+        match LogFile::create(&path) {
+            Ok(log) => listeners.push(Box::new(log)),
+            Err(err) => {
+                writeln!(&mut std::io::stderr(), "Error: can't open {}: {}", path, err).unwrap();
+                std::process::exit(exit_fatal);
+            },
+        }
+    }
+    if daemon {
+        if let Some(notify) = SystemdNotify::from_env() {
+            notify.ready();
+            listeners.push(Box::new(notify));
+        }
+    }
+    if let Some(command) = matches.opt_str("exec") {
+        listeners.push(Box::new(ExecHook::new(command)));
     }
+    if let Some(path) = matches.opt_str("export-hashes") {
+        s.settings.full_hashes = true;
+        listeners.push(Box::new(HashExport::new(path)));
+    }
+    let status = Rc::new(ExitStatus::default());
+    listeners.push(Box::new(ExitStatusListener(Rc::clone(&status))));
+    s.set_listener(Box::new(listeners));
 
     s.exclude(matches.opt_strs("exclude"));
+    for path in matches.opt_strs("exclude-from") {
+        match ExcludeRules::from_file(&path) {
+            Ok(rules) => s.exclude_rules(rules),
+            Err(err) => {
+                writeln!(&mut std::io::stderr(), "Error: can't read {}: {}", path, err).unwrap();
+                std::process::exit(exit_fatal);
+            },
+        }
+    }
 
-    match inner_main(s, matches.free) {
+    for target in matches.opt_strs("remote") {
+        match remote_hashes(&target) {
+            Ok(manifest) => s.import_hashes(manifest),
+            Err(err) => {
+                writeln!(&mut std::io::stderr(), "Error: can't get hashes from {}: {}", target, err).unwrap();
+                std::process::exit(exit_fatal);
+            },
+        }
+    }
+    let remap: Vec<_> = matches.opt_strs("remap").into_iter().map(|arg| match arg.split_once('=') {
+        Some((from, to)) if !from.is_empty() && !to.is_empty() => (PathBuf::from(from), PathBuf::from(to)),
+        _ => {
+            writeln!(&mut std::io::stderr(), "Error: invalid --remap value '{}'. Expected <dir in the file>=<dir here>", arg).unwrap();
+            std::process::exit(exit_fatal);
+        },
+    }).collect();
+    for path in matches.opt_strs("import-hashes") {
+        match HashManifest::from_file_remapped(&path, &remap) {
+            Ok(manifest) => s.import_hashes(manifest),
+            Err(err) => {
+                writeln!(&mut std::io::stderr(), "Error: can't read {}: {}", path, err).unwrap();
+                std::process::exit(exit_fatal);
+            },
+        }
+    }
+
+    let mut protect = ExcludeRules::new();
+    for pattern in matches.opt_strs("protect") {
+        protect.add_line(&pattern);
+    }
+    s.protect_rules(protect);
+    let mut keep_matching = ExcludeRules::new();
+    for pattern in matches.opt_strs("keep-matching") {
+        keep_matching.add_line(&pattern);
+    }
+    s.keep_matching(keep_matching);
+
+    if let Some(path) = matches.opt_str("undo-log") {
+        match UndoLog::create(&path) {
+            Ok(undo_log) => s.set_undo_log(undo_log),
+            Err(err) => {
+                writeln!(&mut std::io::stderr(), "Error: can't open {}: {}", path, err).unwrap();
+                std::process::exit(exit_fatal);
+            },
+        }
+    }
+
+    let res = match command {
+        Command::Scan | Command::Compare => inner_main(s, free, matches.opt_strs("reference"), matches.opt_strs("prefer-root"), matches.opt_str("checkpoint").map(PathBuf::from)),
+        Command::Apply => apply_reports(s, free, matches.opt_strs("reference"), matches.opt_strs("prefer-root")),
+        Command::Verify | Command::Undo | Command::Stats | Command::Merge => unreachable!(),
+    };
+    match res {
         Ok(()) => {},
         Err(err) => {
             writeln!(&mut std::io::stderr(), "Error: {}", err).unwrap();
-            std::process::exit(1);
+            std::process::exit(exit_fatal);
         },
     };
+    if status.errors.get() > 0 {
+        std::process::exit(exit_file_errors);
+    }
+    if matches.opt_present("fail-on-dupes") && status.dupes.get() > 0 {
+        std::process::exit(EXIT_DUPES_FOUND);
+    }
+}
+
+/// Idle I/O class and the lowest CPU priority, for the whole process
+fn lower_priority() -> io::Result<()> {
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 19) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    lower_io_priority()
+}
+
+#[cfg(target_os = "linux")]
+fn lower_io_priority() -> io::Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn lower_io_priority() -> io::Result<()> {
+    extern "C" {
+        fn setiopolicy_np(iotype: libc::c_int, scope: libc::c_int, policy: libc::c_int) -> libc::c_int;
+    }
+    const IOPOL_TYPE_DISK: libc::c_int = 0;
+    const IOPOL_SCOPE_PROCESS: libc::c_int = 0;
+    const IOPOL_THROTTLE: libc::c_int = 3;
+    if unsafe { setiopolicy_np(IOPOL_TYPE_DISK, IOPOL_SCOPE_PROCESS, IOPOL_THROTTLE) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
 }
 
-fn inner_main(mut s: Scanner, args: Vec<String>) -> io::Result<()> {
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn lower_io_priority() -> io::Result<()> {
+    Ok(())
+}
+
+fn id_or_exit(opt: &str, name: &str, id: Option<u32>) -> u32 {
+    id.unwrap_or_else(|| {
+        writeln!(&mut std::io::stderr(), "Error: unknown --{} '{}'", opt, name).unwrap();
+        std::process::exit(EXIT_FATAL);
+    })
+}
+
+fn lookup_uid(name: &str) -> Option<u32> {
+    if let Ok(uid) = name.parse() {
+        return Some(uid);
+    }
+    let name = CString::new(name).ok()?;
+    // Called once at startup, before any threads could use getpw* too
+    let pw = unsafe { libc::getpwnam(name.as_ptr()) };
+    if pw.is_null() {
+        return None;
+    }
+    Some(unsafe { (*pw).pw_uid })
+}
+
+fn lookup_gid(name: &str) -> Option<u32> {
+    if let Ok(gid) = name.parse() {
+        return Some(gid);
+    }
+    let name = CString::new(name).ok()?;
+    let gr = unsafe { libc::getgrnam(name.as_ptr()) };
+    if gr.is_null() {
+        return None;
+    }
+    Some(unsafe { (*gr).gr_gid })
+}
+
+/// Exits on invalid durations
+fn duration_opt(matches: &getopts::Matches, name: &str) -> Option<Duration> {
+    let arg = matches.opt_str(name)?;
+    match parse_duration(&arg) {
+        Some(d) => Some(d),
+        None => {
+            writeln!(&mut std::io::stderr(), "Error: invalid --{} value '{}'. Expected a number with a unit: s, m, h, d, or w", name, arg).unwrap();
+            std::process::exit(EXIT_FATAL);
+        },
+    }
+}
+
+/// Parses durations like "90d" or "1h30m"
+fn parse_duration(s: &str) -> Option<Duration> {
+    let mut total = 0u64;
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return None;
+    }
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let num: u64 = rest[..digits].parse().ok()?;
+        rest = &rest[digits..];
+        let unit = rest.chars().next()?;
+        rest = &rest[unit.len_utf8()..];
+        let secs = match unit {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return None,
+        };
+        total = total.checked_add(num.checked_mul(secs)?)?;
+    }
+    Some(Duration::from_secs(total))
+}
+
+/// Parses sizes like "4096", "500M" or "1.5GB" (in units of 1024)
+fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let s = s.strip_suffix("iB").or_else(|| s.strip_suffix('B')).unwrap_or(s);
+    let (num, unit) = match s.find(|c: char| !c.is_ascii_digit() && c != '.') {
+        Some(pos) => s.split_at(pos),
+        None => (s, ""),
+    };
+    let multiplier = match unit.to_ascii_uppercase().as_str() {
+        "" => 1u64,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return None,
+    };
+    // Only digits and dots, so it can't be negative or NaN
+    let num: f64 = num.parse().ok()?;
+    Some((num * multiplier as f64) as u64)
+}
+
+fn chunk_size_or_exit(opt: &str, arg: &str) -> u32 {
+    parse_size(arg).filter(|&size| size > 0).and_then(|size| size.try_into().ok()).unwrap_or_else(|| {
+        writeln!(&mut std::io::stderr(), "Error: invalid --{} value '{}'. Expected a size between 1 byte and 4G, optionally with a unit: K, M, or G", opt, arg).unwrap();
+        std::process::exit(EXIT_FATAL);
+    })
+}
+
+#[test]
+fn parse_size_test() {
+    assert_eq!(parse_size("4096"), Some(4096));
+    assert_eq!(parse_size("500M"), Some(500 << 20));
+    assert_eq!(parse_size("1.5GB"), Some(3 << 29));
+    assert_eq!(parse_size("2kiB"), Some(2048));
+    assert_eq!(parse_size("10X"), None);
+    assert_eq!(parse_size("G"), None);
+}
+
+#[test]
+fn parse_duration_test() {
+    assert_eq!(parse_duration("90d"), Some(Duration::from_secs(90 * 86400)));
+    assert_eq!(parse_duration("1h30m"), Some(Duration::from_secs(5400)));
+    assert_eq!(parse_duration("15s"), Some(Duration::from_secs(15)));
+    assert_eq!(parse_duration("15"), None);
+    assert_eq!(parse_duration("d"), None);
+    assert_eq!(parse_duration(""), None);
+}
+
+fn inner_main(mut s: Scanner, args: Vec<String>, references: Vec<String>, preferred: Vec<String>, checkpoint_path: Option<PathBuf>) -> io::Result<()> {
+    if let Some(checkpoint_path) = checkpoint_path.as_ref().filter(|p| p.exists()) {
+        s.resume(Checkpoint::load(checkpoint_path)?)?;
+    }
+    // Roots that are in the checkpoint are skipped
+    for reference in references {
+        s.enqueue_reference(reference)?;
+    }
+    for path in preferred {
+        s.enqueue_preferred(path)?;
+    }
     for arg in args {
         let path: PathBuf = arg.into();
         s.enqueue(path)?;
     }
+    s.flush()?;
+
+    if let Some(checkpoint_path) = checkpoint_path {
+        if s.stopped() {
+            s.checkpoint().save(&checkpoint_path)?;
+            writeln!(&mut std::io::stderr(), "Saved progress to {}. Run again with the same --checkpoint to continue.", checkpoint_path.display()).unwrap();
+        } else if checkpoint_path.exists() {
+            fs::remove_file(&checkpoint_path)?;
+        }
+    }
+    Ok(())
+}
+
+fn verify_main(program: &str, args: Vec<String>) {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "This help text");
+    let matches = parse_args_or_exit(&opts, args);
+    if matches.opt_present("h") || matches.free.is_empty() {
+        println!("Checks that files linked earlier are still identical and hardlinked, and exits with an error if any aren't.\n\n{}", opts.usage(&commands_usage(program)));
+        return;
+    }
+    match verify_reports(matches.free) {
+        Ok(0) => {},
+        Ok(diverged) => {
+            writeln!(&mut std::io::stderr(), "{} groups have diverged", diverged).unwrap();
+            std::process::exit(EXIT_DUPES_FOUND);
+        },
+        Err(err) => {
+            writeln!(&mut std::io::stderr(), "Error: {}", err).unwrap();
+            std::process::exit(EXIT_FATAL);
+        },
+    }
+}
+
+fn stats_main(program: &str, args: Vec<String>) {
+    let mut opts = Options::new();
+    opts.optopt("", "top", "Number of largest groups to list (default 10)", "<number>");
+    opts.optflag("", "bytes", "Print sizes in bytes instead of KiB, MiB, etc.");
+    opts.optflag("h", "help", "This help text");
+    let matches = parse_args_or_exit(&opts, args);
+    if matches.opt_present("h") || matches.free.is_empty() {
+        println!("Prints total savings, the largest groups, and savings by file extension from reports saved with --json.\n\n{}", opts.usage(&commands_usage(program)));
+        return;
+    }
+    let top = matches.opt_str("top").map_or(10, |arg| arg.parse().unwrap_or_else(|_| {
+        writeln!(&mut std::io::stderr(), "Error: invalid --top value '{}'", arg).unwrap();
+        std::process::exit(EXIT_FATAL);
+    }));
+    let exact_bytes = matches.opt_present("bytes");
+    if let Err(err) = report_stats(matches.free, top, exact_bytes) {
+        writeln!(&mut std::io::stderr(), "Error: {}", err).unwrap();
+        std::process::exit(EXIT_FATAL);
+    }
+}
+
+fn merge_main(program: &str, args: Vec<String>) {
+    let mut opts = Options::new();
+    opts.optopt("o", "output", "Write the merged report to this file instead of stdout", "<file>");
+    opts.optflag("h", "help", "This help text");
+    let matches = parse_args_or_exit(&opts, args);
+    if matches.opt_present("h") || matches.free.is_empty() {
+        println!("Combines JSON reports of separate scans into one, merging groups of files with the same content.\n\n{}", opts.usage(&commands_usage(program)));
+        return;
+    }
+    let output = matches.opt_str("output");
+    if let Err(err) = merge_json_reports(matches.free, output) {
+        writeln!(&mut std::io::stderr(), "Error: {}", err).unwrap();
+        std::process::exit(EXIT_FATAL);
+    }
+}
+
+fn undo_main(program: &str, args: Vec<String>) {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "This help text");
+    let matches = parse_args_or_exit(&opts, args);
+    if matches.opt_present("h") || matches.free.is_empty() {
+        println!("Gives every file replaced by a run with --undo-log its own copy again.\n\n{}", opts.usage(&commands_usage(program)));
+        return;
+    }
+    let status = ExitStatus::default();
+    if let Err(err) = undo_logs(matches.free, &status) {
+        writeln!(&mut std::io::stderr(), "Error: {}", err).unwrap();
+        std::process::exit(EXIT_FATAL);
+    }
+    if status.errors.get() > 0 {
+        std::process::exit(EXIT_FILE_ERRORS);
+    }
+}
+
+fn undo_logs(logs: Vec<String>, status: &ExitStatus) -> io::Result<()> {
+    for log in logs {
+        let stats = undo(&log)?;
+        status.errors.set(status.errors.get() + stats.errors);
+        println!("Restored {} files. {} were not hardlinked anymore. {} errors.", stats.restored, stats.unchanged, stats.errors);
+    }
+    Ok(())
+}
+
+/// Links files listed in JSON reports saved earlier with `--json`
+#[cfg(feature = "json")]
+fn apply_reports(mut s: Scanner, reports: Vec<String>, references: Vec<String>, preferred: Vec<String>) -> io::Result<()> {
+    // Files in the report have already passed the size check when it was made
+    s.settings.ignore_small = false;
+    for reference in references {
+        s.add_reference_root(reference)?;
+    }
+    for path in preferred {
+        s.add_preferred_root(path)?;
+    }
+    for report in reports {
+        let report = JsonReport::from_file(&report)?;
+        // Rules relative to roots, like --protect, apply the same way as in the scan. Reports from older versions don't have roots.
+        let roots: Vec<&Path> = if !report.roots.is_empty() {
+            report.roots.iter().map(|root| &**root).collect()
+        } else {
+            report.dupes.iter().flatten().flatten().filter_map(|path| path.parent()).collect()
+        };
+        for root in roots {
+            if let Err(err) = s.add_root(root) {
+                writeln!(&mut std::io::stderr(), "{}: {}", root.display(), err).unwrap();
+            }
+        }
+        // Files are compared again, so only ones that are still identical will be linked, and only with files of the same group
+        for group in report.dupes {
+            s.add_group(group.into_iter().flatten().map(|path| PathBuf::from(path.0)));
+        }
+    }
     s.flush()
 }
+
+#[cfg(not(feature = "json"))]
+fn apply_reports(_: Scanner, _: Vec<String>, _: Vec<String>, _: Vec<String>) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "This binary was compiled without JSON support."))
+}
+
+#[cfg(feature = "json")]
+fn merge_json_reports(reports: Vec<String>, output: Option<String>) -> io::Result<()> {
+    let reports = reports.iter()
+        .map(|path| JsonReport::from_file(path).map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path, err))))
+        .collect::<io::Result<_>>()?;
+    let merged = merge_reports(reports)?;
+    match output {
+        Some(path) => merged.write(io::BufWriter::new(fs::File::create(path)?)),
+        None => merged.write(io::stdout().lock()),
+    }
+}
+
+#[cfg(not(feature = "json"))]
+fn merge_json_reports(_: Vec<String>, _: Option<String>) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "This binary was compiled without JSON support."))
+}
+
+#[cfg(feature = "json")]
+fn report_stats(reports: Vec<String>, top: usize, exact_bytes: bool) -> io::Result<()> {
+    for (i, report) in reports.iter().enumerate() {
+        if reports.len() > 1 {
+            println!("{}{}:", if i > 0 { "\n" } else { "" }, report);
+        }
+        ReportSummary::new(&JsonReport::from_file(report)?, top).print(exact_bytes);
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "json"))]
+fn report_stats(_: Vec<String>, _: usize, _: bool) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "This binary was compiled without JSON support."))
+}
+
+/// Checks that groups in JSON reports saved earlier are still identical and linked. Returns the number of groups that aren't.
+#[cfg(feature = "json")]
+fn verify_reports(reports: Vec<String>) -> io::Result<usize> {
+    let mut diverged = 0;
+    for report in reports {
+        let stats = verify(&JsonReport::from_file(&report)?);
+        println!("{}: {} groups intact, {} diverged.", report, stats.intact, stats.diverged);
+        diverged += stats.diverged;
+    }
+    Ok(diverged)
+}
+
+#[cfg(not(feature = "json"))]
+fn verify_reports(_: Vec<String>) -> io::Result<usize> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "This binary was compiled without JSON support."))
+}
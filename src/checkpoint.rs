@@ -0,0 +1,74 @@
+use crate::undo::{escape, unescape};
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const HEADER: &str = "# dupe-krill checkpoint v1";
+
+/// State of an interrupted scan, from `Scanner::checkpoint()`.
+///
+/// Files that have been scanned are listed by path only, and are compared again after resuming.
+/// This is still much faster than starting over, since files that have already been linked are recognized as hardlinks.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Paths as given to `Scanner::enqueue`
+    pub roots: Vec<PathBuf>,
+    /// Files that have been scanned
+    pub files: Vec<PathBuf>,
+    /// Directories that haven't been scanned yet
+    pub dirs: Vec<PathBuf>,
+}
+
+impl Checkpoint {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut checkpoint = Self::default();
+        for line in fs::read_to_string(path)?.lines() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("Invalid checkpoint line: {}", line));
+            let (kind, path) = line.split_once('\t').ok_or_else(invalid)?;
+            let path = unescape(path).ok_or_else(invalid)?;
+            match kind {
+                "root" => checkpoint.roots.push(path),
+                "file" => checkpoint.files.push(path),
+                "dir" => checkpoint.dirs.push(path),
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(checkpoint)
+    }
+
+    /// Written to a temporary file first, so that an older checkpoint isn't lost if this fails
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut temp_name = path.file_name().unwrap_or_default().to_owned();
+        temp_name.push(".tmp");
+        let temp_path = path.with_file_name(temp_name);
+
+        let mut file = io::BufWriter::new(fs::File::create(&temp_path)?);
+        writeln!(file, "{}", HEADER)?;
+        for (kind, paths) in [("root", &self.roots), ("file", &self.files), ("dir", &self.dirs)] {
+            for path in paths {
+                writeln!(file, "{}\t{}", kind, escape(path))?;
+            }
+        }
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&temp_path, path)
+    }
+}
+
+#[test]
+fn checkpoint_roundtrip() {
+    let dir = std::env::temp_dir().join(format!("dupe-krill-checkpoint-{}", std::process::id()));
+    let checkpoint = Checkpoint {
+        roots: vec!["/a".into(), "b".into()],
+        files: vec!["/a/x\ty".into(), "b/z".into()],
+        dirs: vec!["/a/sub".into()],
+    };
+    checkpoint.save(&dir).unwrap();
+    let loaded = Checkpoint::load(&dir);
+    let _ = fs::remove_file(&dir);
+    assert_eq!(checkpoint, loaded.unwrap());
+}
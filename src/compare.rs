@@ -0,0 +1,97 @@
+use crate::scanner::ScanListener;
+use crate::scanner::Scanner;
+use crate::scanner::Stats;
+use crate::ui::human_size;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Reports which files in the `checked` directory already have a copy with the same content in the `base` one.
+///
+/// Lines start with `=` for files that are in `base` too (followed by the path of the copy), and `+` for files that are only in `checked`.
+/// Meant to be used with `RunMode::DryRunNoMerging`.
+#[derive(Debug)]
+pub struct CompareOutput {
+    base: PathBuf,
+    checked: PathBuf,
+    summary_only: bool,
+    exact_bytes: bool,
+}
+
+impl CompareOutput {
+    pub fn new(base: impl Into<PathBuf>, checked: impl Into<PathBuf>) -> Self {
+        CompareOutput {
+            base: base.into(),
+            checked: checked.into(),
+            summary_only: false,
+            exact_bytes: false,
+        }
+    }
+
+    /// Don't list files, only print the totals
+    pub fn set_summary_only(&mut self, summary_only: bool) {
+        self.summary_only = summary_only;
+    }
+
+    pub fn set_exact_bytes(&mut self, exact_bytes: bool) {
+        self.exact_bytes = exact_bytes;
+    }
+
+    fn size(&self, size: u64) -> String {
+        if self.exact_bytes {
+            format!("{}B", size)
+        } else {
+            human_size(size as usize)
+        }
+    }
+}
+
+impl ScanListener for CompareOutput {
+    fn file_scanned(&mut self, _: &Path, _: &Stats) {
+        // output only at scan_over
+    }
+
+    fn scan_over(&self, scanner: &Scanner, _: &Stats, _: Duration) {
+        // `checked` is tested first, in case it's inside `base`
+        let is_checked = |path: &Path| path.starts_with(&self.checked);
+        let mut files = Vec::new();
+        for group in scanner.dupes() {
+            let paths: Vec<_> = group.into_iter().flat_map(|set| set.paths).collect();
+            let copy_in_base = paths.iter().find(|path| !is_checked(path) && path.starts_with(&self.base)).cloned();
+            files.extend(paths.into_iter().filter(|path| is_checked(path)).map(|path| (path, copy_in_base.clone())));
+        }
+        files.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+        let (mut in_base, mut in_base_bytes, mut only_checked, mut only_checked_bytes) = (0, 0, 0, 0);
+        for (path, copy_in_base) in files {
+            let size = fs::symlink_metadata(&path).map_or(0, |m| m.len());
+            if let Some(copy) = copy_in_base {
+                in_base += 1;
+                in_base_bytes += size;
+                if !self.summary_only {
+                    println!("= {}  {}", path.display(), copy.display());
+                }
+            } else {
+                only_checked += 1;
+                only_checked_bytes += size;
+                if !self.summary_only {
+                    println!("+ {}", path.display());
+                }
+            }
+        }
+        println!("{} files ({}) in {} have a copy in {}. {} files ({}) are only in {}.",
+            in_base, self.size(in_base_bytes), self.checked.display(), self.base.display(),
+            only_checked, self.size(only_checked_bytes), self.checked.display());
+        if scanner.stopped() {
+            eprintln!("The scan has been interrupted, so some files haven't been compared.");
+        }
+    }
+
+    fn hardlinked(&mut self, _: &Path, _: &Path) {
+        // output only at scan_over
+    }
+
+    fn duplicate_found(&mut self, _: &Path, _: &Path) {
+        // output only at scan_over
+    }
+}
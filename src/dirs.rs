@@ -0,0 +1,175 @@
+use crate::scanner::Scanner;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Node {
+    /// Index of the content group
+    File(usize),
+    /// Id of the directory's signature
+    Dir(usize),
+}
+
+/// Two directories that have files in common, from `Scanner::similar_dirs()`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimilarDirs {
+    pub dirs: (PathBuf, PathBuf),
+    /// Size of the files that are in both directories
+    pub common_bytes: u64,
+    /// `common_bytes` as a fraction of the size of the larger directory (not counting subdirectories)
+    pub similarity: f64,
+}
+
+/// Files copied into many directories (like licenses) are rarely interesting, and would make a lot of pairs
+const MAX_DIRS_PER_GROUP: usize = 50;
+
+#[derive(Debug, Default)]
+struct DirInfo {
+    entries: Vec<(OsString, Node)>,
+    signature: Option<usize>,
+    complete: bool,
+}
+
+impl Scanner {
+    /// Finds directories that have identical contents (same file names with the same content, recursively).
+    ///
+    /// Directories are compared only if all of their files have been scanned,
+    /// so directories with excluded or skipped (e.g. small) files are never reported.
+    /// Subdirectories of duplicate directories aren't reported separately.
+    pub fn duplicate_dirs(&self) -> Vec<Vec<PathBuf>> {
+        let mut dirs: HashMap<PathBuf, DirInfo> = HashMap::new();
+        for (group_id, group) in self.dupes().into_iter().enumerate() {
+            for path in group.iter().flat_map(|set| set.paths.iter()) {
+                if let (Some(parent), Some(name)) = (path.parent(), path.file_name()) {
+                    dirs.entry(parent.to_path_buf()).or_default().entries.push((name.to_owned(), Node::File(group_id)));
+                }
+            }
+        }
+
+        // Directories are processed deepest first, so that subdirectories are always known before their parents
+        let mut by_depth: Vec<PathBuf> = Vec::with_capacity(dirs.len());
+        let mut pending: Vec<PathBuf> = dirs.keys().cloned().collect();
+        while let Some(dir) = pending.pop() {
+            by_depth.push(dir.clone());
+            if self.roots().iter().any(|root| **root == *dir) {
+                continue;
+            }
+            if let Some(parent) = dir.parent().filter(|p| self.roots().iter().any(|root| p.starts_with(root))) {
+                let is_new = !dirs.contains_key(parent);
+                // Signature ids aren't known yet, so they're filled in later
+                dirs.entry(parent.to_path_buf()).or_default().entries.push((dir.file_name().unwrap_or_default().to_owned(), Node::Dir(usize::MAX)));
+                if is_new {
+                    pending.push(parent.to_path_buf());
+                }
+            }
+        }
+        by_depth.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+
+        let mut signatures: HashMap<Vec<(OsString, Node)>, usize> = HashMap::new();
+        let mut signature_counts: Vec<usize> = Vec::new();
+        for dir in &by_depth {
+            let mut entries = std::mem::take(&mut dirs.get_mut(dir).unwrap().entries);
+            for (name, node) in &mut entries {
+                if let Node::Dir(id) = node {
+                    *id = dirs.get(&dir.join(&*name)).and_then(|d| d.signature).unwrap_or(usize::MAX);
+                }
+            }
+            entries.sort();
+            let next_id = signatures.len();
+            let id = *signatures.entry(entries.clone()).or_insert(next_id);
+            if id == signature_counts.len() {
+                signature_counts.push(0);
+            }
+            signature_counts[id] += 1;
+            let info = dirs.get_mut(dir).unwrap();
+            info.entries = entries;
+            info.signature = Some(id);
+        }
+
+        // Only directories that have a twin need to be checked for files that haven't been scanned
+        for dir in &by_depth {
+            let info = &dirs[dir];
+            if info.signature.map_or(true, |id| signature_counts[id] < 2) {
+                continue;
+            }
+            let subdirs_complete = info.entries.iter().all(|(name, node)| match node {
+                Node::Dir(_) => dirs.get(&dir.join(name)).map_or(false, |d| d.complete),
+                Node::File(_) => true,
+            });
+            let complete = subdirs_complete && fs::read_dir(dir).map_or(false, |entries| entries.count() == info.entries.len());
+            dirs.get_mut(dir).unwrap().complete = complete;
+        }
+
+        let mut groups: HashMap<usize, Vec<PathBuf>> = HashMap::new();
+        for (dir, info) in &dirs {
+            if let (true, Some(id)) = (info.complete, info.signature) {
+                groups.entry(id).or_default().push(dir.clone());
+            }
+        }
+        groups.retain(|_, group| group.len() > 1);
+
+        let is_dupe = |dir: &Path| dirs.get(dir).and_then(|d| d.signature).map_or(false, |id| groups.contains_key(&id) && dirs[dir].complete);
+        let mut out: Vec<Vec<PathBuf>> = groups.values()
+            .filter(|group| !group.iter().all(|dir| dir.parent().map_or(false, &is_dupe)))
+            .cloned()
+            .map(|mut group| {
+                group.sort();
+                group
+            })
+            .collect();
+        out.sort();
+        out
+    }
+
+    /// Pairs of directories that share files with the same content, largest amount of shared data first.
+    /// Unlike `duplicate_dirs()`, the directories don't need to be identical.
+    pub fn similar_dirs(&self) -> Vec<SimilarDirs> {
+        let mut common: HashMap<(PathBuf, PathBuf), u64> = HashMap::new();
+        self.for_each_group(|size, group| {
+            if group.iter().map(|set| set.paths.len()).sum::<usize>() < 2 {
+                return;
+            }
+            let mut copies: HashMap<&Path, u64> = HashMap::new();
+            for path in group.iter().flat_map(|set| set.paths.iter()) {
+                if let Some(parent) = path.parent() {
+                    *copies.entry(parent).or_insert(0) += 1;
+                }
+            }
+            if copies.len() < 2 || copies.len() > MAX_DIRS_PER_GROUP {
+                return;
+            }
+            let mut copies: Vec<_> = copies.into_iter().collect();
+            copies.sort_unstable();
+            for (i, &(a, a_count)) in copies.iter().enumerate() {
+                for &(b, b_count) in &copies[i + 1..] {
+                    *common.entry((a.to_path_buf(), b.to_path_buf())).or_insert(0) += size * a_count.min(b_count);
+                }
+            }
+        });
+        if common.is_empty() {
+            return Vec::new();
+        }
+
+        // Sizes include unique files, but only directories that have something in common are needed
+        let mut dir_sizes: HashMap<PathBuf, u64> = common.keys().flat_map(|(a, b)| [(a.clone(), 0), (b.clone(), 0)]).collect();
+        self.for_each_group(|size, group| {
+            for path in group.iter().flat_map(|set| set.paths.iter()) {
+                if let Some(dir_size) = path.parent().and_then(|parent| dir_sizes.get_mut(parent)) {
+                    *dir_size += size;
+                }
+            }
+        });
+        let mut out: Vec<_> = common.into_iter().map(|(dirs, common_bytes)| {
+            let larger = dir_sizes[&dirs.0].max(dir_sizes[&dirs.1]);
+            SimilarDirs {
+                similarity: if larger > 0 { common_bytes as f64 / larger as f64 } else { 1. },
+                dirs,
+                common_bytes,
+            }
+        }).collect();
+        out.sort_by(|a, b| b.common_bytes.cmp(&a.common_bytes).then_with(|| a.dirs.cmp(&b.dirs)));
+        out
+    }
+}
@@ -0,0 +1,285 @@
+use std::borrow::Cow;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Exclude/include patterns with the same syntax as rsync's `--exclude-from` files.
+///
+/// * `*` matches anything except `/`, `**` matches anything, `?` matches one character, `[a-z]` matches a class.
+/// * A leading `/` anchors the pattern at the scan root, otherwise it matches the end of the path.
+/// * Patterns without `/` or `**` match only the file name.
+/// * A trailing `/` matches only directories.
+/// * Lines can start with `- ` (exclude, the default) or `+ ` (include). The first matching rule wins.
+/// * Empty lines and lines starting with `#` or `;` are ignored.
+#[derive(Debug, Default, Clone)]
+pub struct ExcludeRules {
+    rules: Vec<Rule>,
+}
+
+/// Directories that are skipped unless `Settings::default_excludes` is off.
+/// Their files are either deduplicated already (by content-addressed storage), or are caches that change all the time.
+const DEFAULT_EXCLUDES: &[&str] = &[
+    ".git/objects/",
+    ".hg/",
+    ".svn/",
+    "node_modules/.cache/",
+    ".cache/mozilla/",
+    ".cache/google-chrome/",
+    ".cache/chromium/",
+    ".cache/thumbnails/",
+    "Library/Caches/",
+];
+
+/// Finder and Spotlight metadata, and AppleDouble files with resource forks on non-Apple filesystems.
+/// They're not user files, and operations on them fail on exFAT drives.
+#[cfg(target_os = "macos")]
+const MACOS_EXCLUDES: &[&str] = &[
+    ".DS_Store",
+    "._*",
+    ".Spotlight-V100/",
+    ".fseventsd/",
+];
+
+#[derive(Debug, Clone)]
+struct Rule {
+    include: bool,
+    anchored: bool,
+    dir_only: bool,
+    pattern: Vec<u8>,
+}
+
+impl ExcludeRules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Built-in rules for version control and cache directories, and macOS metadata files
+    pub fn defaults() -> Self {
+        let mut rules = Self::new();
+        for line in DEFAULT_EXCLUDES {
+            rules.add_line(line);
+        }
+        #[cfg(target_os = "macos")]
+        for line in MACOS_EXCLUDES {
+            rules.add_line(line);
+        }
+        rules
+    }
+
+    /// Reads rules from a file, one per line
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut rules = Self::new();
+        for line in fs::read_to_string(path)?.lines() {
+            rules.add_line(line);
+        }
+        Ok(rules)
+    }
+
+    /// Adds a rule in the rsync filter file syntax. Comments and empty lines are ignored.
+    pub fn add_line(&mut self, line: &str) {
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            return;
+        }
+        let (include, pattern) = if let Some(p) = line.strip_prefix("+ ") {
+            (true, p)
+        } else if let Some(p) = line.strip_prefix("- ") {
+            (false, p)
+        } else {
+            (false, line)
+        };
+        let (dir_only, pattern) = match pattern.strip_suffix('/') {
+            Some(p) if !p.is_empty() => (true, p),
+            _ => (false, pattern),
+        };
+        let (anchored, pattern) = match pattern.strip_prefix('/') {
+            Some(p) => (true, p),
+            None => (false, pattern),
+        };
+        self.rules.push(Rule {
+            include,
+            anchored,
+            dir_only,
+            pattern: normalized(pattern.as_bytes()).into_owned(),
+        });
+    }
+
+    pub fn extend(&mut self, other: ExcludeRules) {
+        self.rules.extend(other.rules);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// `rel_path` is relative to the scan root
+    pub fn is_excluded(&self, rel_path: &Path, is_dir: bool) -> bool {
+        self.first_match(rel_path, is_dir).unwrap_or(false)
+    }
+
+    /// `Some(excluded)` if any rule matches, so that other rules can be checked if none does
+    pub(crate) fn first_match(&self, rel_path: &Path, is_dir: bool) -> Option<bool> {
+        let path = normalized(rel_path.as_os_str().as_bytes());
+        let path = &*path;
+        self.rules.iter()
+            .filter(|rule| is_dir || !rule.dir_only)
+            .find(|rule| rule.matches(path))
+            .map(|rule| !rule.include)
+    }
+}
+
+impl Rule {
+    fn matches(&self, path: &[u8]) -> bool {
+        if self.anchored {
+            return glob_match(&self.pattern, path);
+        }
+        // Try every suffix of the path that starts at a path component
+        if glob_match(&self.pattern, path) {
+            return true;
+        }
+        path.iter().enumerate()
+            .filter(|&(_, &c)| c == b'/')
+            .any(|(i, _)| glob_match(&self.pattern, &path[i + 1..]))
+    }
+}
+
+/// macOS filesystems may return file names in decomposed form (NFD), but user input is usually composed (NFC).
+/// Both are compared as NFC. Elsewhere names are compared byte by byte.
+#[cfg(target_os = "macos")]
+pub(crate) fn normalized(name: &[u8]) -> Cow<'_, [u8]> {
+    use unicode_normalization::{is_nfc_quick, IsNormalized, UnicodeNormalization};
+
+    match std::str::from_utf8(name) {
+        Ok(s) if is_nfc_quick(s.chars()) != IsNormalized::Yes => Cow::Owned(s.nfc().collect::<String>().into_bytes()),
+        _ => Cow::Borrowed(name),
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+pub(crate) fn normalized(name: &[u8]) -> Cow<'_, [u8]> {
+    Cow::Borrowed(name)
+}
+
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some(b'*') if pattern.get(1) == Some(&b'*') => {
+            let rest = &pattern[2..];
+            (0..=text.len()).any(|i| glob_match(rest, &text[i..]))
+        },
+        Some(b'*') => {
+            let rest = &pattern[1..];
+            for i in 0..=text.len() {
+                if glob_match(rest, &text[i..]) {
+                    return true;
+                }
+                if text.get(i) == Some(&b'/') {
+                    break;
+                }
+            }
+            false
+        },
+        Some(b'?') => match text.first() {
+            Some(&c) if c != b'/' => glob_match(&pattern[1..], &text[1..]),
+            _ => false,
+        },
+        Some(b'[') => match (text.first(), char_class(pattern)) {
+            (Some(&c), Some((class, len))) => c != b'/' && class_contains(class, c) && glob_match(&pattern[len..], &text[1..]),
+            (Some(&c), None) => c == b'[' && glob_match(&pattern[1..], &text[1..]),
+            (None, _) => false,
+        },
+        Some(b'\\') if pattern.len() > 1 => text.first() == Some(&pattern[1]) && glob_match(&pattern[2..], &text[1..]),
+        Some(&p) => text.first() == Some(&p) && glob_match(&pattern[1..], &text[1..]),
+    }
+}
+
+/// Returns contents of `[…]` and length of the whole class in the pattern, or `None` if it's not terminated
+fn char_class(pattern: &[u8]) -> Option<(&[u8], usize)> {
+    // `]` right after the opening bracket (or negation) is a literal
+    let mut start = 1;
+    if matches!(pattern.get(start), Some(b'!' | b'^')) {
+        start += 1;
+    }
+    let end = start + 1 + pattern.get(start + 1..)?.iter().position(|&c| c == b']')?;
+    Some((&pattern[1..end], end + 1))
+}
+
+fn class_contains(class: &[u8], c: u8) -> bool {
+    let (negated, class) = match class.first() {
+        Some(b'!' | b'^') => (true, &class[1..]),
+        _ => (false, class),
+    };
+    let mut found = false;
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == b'-' {
+            found |= (class[i]..=class[i + 2]).contains(&c);
+            i += 3;
+        } else {
+            found |= class[i] == c;
+            i += 1;
+        }
+    }
+    found != negated
+}
+
+#[test]
+fn glob_test() {
+    assert!(glob_match(b"*.txt", b"a.txt"));
+    assert!(!glob_match(b"*.txt", b"a/b.txt"));
+    assert!(glob_match(b"**.txt", b"a/b.txt"));
+    assert!(glob_match(b"a/**/c", b"a/b/b/c"));
+    assert!(glob_match(b"?.[ch]", b"x.h"));
+    assert!(!glob_match(b"?.[!ch]", b"x.h"));
+    assert!(glob_match(b"[]x]", b"]"));
+    assert!(glob_match(b"[0-9][0-9]", b"42"));
+    assert!(glob_match(b"\\*", b"*"));
+    assert!(!glob_match(b"\\*", b"a"));
+    assert!(glob_match(b"[abc", b"[abc"));
+}
+
+#[test]
+fn rules_test() {
+    let mut rules = ExcludeRules::new();
+    for line in ["# comment", "", "+ keep.tmp", "*.tmp", "/build", "cache/", "logs/*.log"] {
+        rules.add_line(line);
+    }
+    assert!(rules.is_excluded(Path::new("a/b.tmp"), false));
+    assert!(!rules.is_excluded(Path::new("a/keep.tmp"), false));
+    assert!(rules.is_excluded(Path::new("build"), true));
+    assert!(!rules.is_excluded(Path::new("src/build"), true));
+    assert!(rules.is_excluded(Path::new("x/cache"), true));
+    assert!(!rules.is_excluded(Path::new("x/cache"), false));
+    assert!(rules.is_excluded(Path::new("x/logs/a.log"), false));
+    assert!(!rules.is_excluded(Path::new("x/logs/y/a.log"), false));
+    assert!(!rules.is_excluded(Path::new("comment"), false));
+}
+
+#[test]
+#[cfg(target_os = "macos")]
+fn normalization_test() {
+    let mut rules = ExcludeRules::new();
+    rules.add_line("caf\u{e9}/");
+    assert!(rules.is_excluded(Path::new("x/cafe\u{301}"), true));
+}
+
+#[test]
+fn defaults_test() {
+    let rules = ExcludeRules::defaults();
+    assert!(rules.is_excluded(Path::new("src/repo/.git/objects"), true));
+    assert!(rules.is_excluded(Path::new("node_modules/.cache"), true));
+    assert!(!rules.is_excluded(Path::new("src/repo/.git"), true));
+    assert!(!rules.is_excluded(Path::new("src/repo/.git/objects"), false));
+    assert_eq!(None, rules.first_match(Path::new("photos"), true));
+}
+
+#[test]
+#[cfg(target_os = "macos")]
+fn macos_defaults_test() {
+    let rules = ExcludeRules::defaults();
+    assert!(rules.is_excluded(Path::new("photos/.DS_Store"), false));
+    assert!(rules.is_excluded(Path::new("photos/._IMG_0001.JPG"), false));
+    assert!(rules.is_excluded(Path::new(".Spotlight-V100"), true));
+    assert!(!rules.is_excluded(Path::new("photos/IMG_0001.JPG"), false));
+}
@@ -0,0 +1,60 @@
+use crate::scanner::ScanListener;
+use crate::scanner::Scanner;
+use crate::scanner::Stats;
+use std::io;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+/// Runs a shell command for every group of duplicates at the end of the scan.
+/// Paths are given to the command as arguments (`"$@"`), and also on stdin, one per line.
+#[derive(Debug)]
+pub struct ExecHook {
+    command: String,
+}
+
+impl ExecHook {
+    pub fn new(command: impl Into<String>) -> Self {
+        ExecHook { command: command.into() }
+    }
+
+    fn run(&self, paths: &[Box<Path>]) -> io::Result<()> {
+        let mut child = Command::new("sh")
+            .arg("-c").arg(&self.command)
+            .arg(env!("CARGO_PKG_NAME"))
+            .args(paths.iter().map(|p| p.as_os_str()))
+            .stdin(Stdio::piped())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // The command doesn't have to read it, so a broken pipe is fine
+            for path in paths {
+                if stdin.write_all(path.as_os_str().as_bytes()).and_then(|_| stdin.write_all(b"\n")).is_err() {
+                    break;
+                }
+            }
+        }
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(io::Error::new(io::ErrorKind::Other, format!("command failed with {}", status)));
+        }
+        Ok(())
+    }
+}
+
+impl ScanListener for ExecHook {
+    fn file_scanned(&mut self, _: &Path, _: &Stats) {}
+
+    fn scan_over(&self, scanner: &Scanner, _: &Stats, _: Duration) {
+        for paths in scanner.dupe_paths() {
+            if let Err(err) = self.run(&paths) {
+                eprintln!("--exec for {}: {}", paths[0].display(), err);
+            }
+        }
+    }
+
+    fn hardlinked(&mut self, _: &Path, _: &Path) {}
+
+    fn duplicate_found(&mut self, _: &Path, _: &Path) {}
+}
@@ -0,0 +1,39 @@
+use crate::scanner::ScanListener;
+use crate::scanner::Scanner;
+use crate::scanner::Stats;
+use std::path::Path;
+use std::time::Duration;
+
+/// Prints groups of duplicates in the same plain format as `fdupes`/`jdupes`:
+/// one path per line, groups separated by a blank line.
+#[derive(Debug, Default)]
+pub struct FdupesOutput;
+
+impl FdupesOutput {
+    pub fn new() -> Self {
+        FdupesOutput
+    }
+}
+
+impl ScanListener for FdupesOutput {
+    fn file_scanned(&mut self, _: &Path, _: &Stats) {
+        // output only at scan_over
+    }
+
+    fn scan_over(&self, scanner: &Scanner, _: &Stats, _: Duration) {
+        for paths in scanner.dupe_paths() {
+            for path in paths {
+                println!("{}", path.display());
+            }
+            println!();
+        }
+    }
+
+    fn hardlinked(&mut self, _: &Path, _: &Path) {
+        // output only at scan_over
+    }
+
+    fn duplicate_found(&mut self, _: &Path, _: &Path) {
+        // output only at scan_over
+    }
+}
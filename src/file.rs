@@ -1,10 +1,13 @@
-use crate::hasher::Hasher;
+use crate::hasher::{ChunkSizes, Hasher};
+use crate::lazyfile::ReadOptions;
 use crate::metadata::Metadata;
 use smallvec::SmallVec;
 use std::cell::RefCell;
 use std::cmp::max;
 use std::cmp::Ordering;
+use std::fs;
 use std::io;
+use std::os::unix::fs::MetadataExt;
 use std::path::Path;
 
 #[derive(Debug, Clone)]
@@ -41,6 +44,8 @@ pub struct FileContent {
     metadata: Metadata,
     /// Hashes of content, calculated incrementally
     hashes: RefCell<Hasher>,
+    /// Compare hashes of whole files instead of reading them bit by bit
+    by_full_hash: bool,
 }
 
 impl FileContent {
@@ -54,8 +59,48 @@ impl FileContent {
             path,
             metadata,
             hashes: RefCell::new(Hasher::new()),
+            by_full_hash: false,
         }
     }
+
+    /// Also keeps a hash of the whole file while comparing, for `full_hash()`
+    pub fn with_full_hash(path: Box<Path>, metadata: Metadata) -> Self {
+        FileContent {
+            path,
+            metadata,
+            hashes: RefCell::new(Hasher::with_full_hash()),
+            by_full_hash: false,
+        }
+    }
+
+    /// Compared using hashes of whole files, so that a `known` hash (e.g. from a manifest) can be used instead of reading the file.
+    /// Files without a known hash are read in full when compared. Don't mix with files made with `new()`.
+    pub fn with_known_hash(path: Box<Path>, metadata: Metadata, known: Option<blake3::Hash>) -> Self {
+        FileContent {
+            path,
+            metadata,
+            hashes: RefCell::new(known.map_or_else(Hasher::new, Hasher::with_known_hash)),
+            by_full_hash: true,
+        }
+    }
+
+    pub fn set_read_options(&mut self, options: ReadOptions) {
+        self.hashes.get_mut().set_read_options(options);
+    }
+
+    /// Both files of a comparison should use the same sizes, since chunks that are already hashed can only be compared with ones of the same size
+    pub fn set_chunk_sizes(&mut self, chunk_sizes: ChunkSizes) {
+        self.hashes.get_mut().set_chunk_sizes(chunk_sizes);
+    }
+
+    pub fn size(&self) -> u64 {
+        self.metadata.size
+    }
+
+    /// BLAKE3 hash of the whole file
+    pub fn full_hash(&self) -> io::Result<blake3::Hash> {
+        self.hashes.borrow().full_hash(&self.path)
+    }
 }
 
 impl Eq for FileContent {}
@@ -98,6 +143,40 @@ impl FileContent {
         let mut hashes1 = self.hashes.borrow_mut();
         let mut hashes2 = other.hashes.borrow_mut();
 
+        if self.by_full_hash && other.by_full_hash {
+            return Ok(hashes1.cached_full_hash(&self.path)?.as_bytes().cmp(hashes2.cached_full_hash(&other.path)?.as_bytes()));
+        }
         hashes1.compare(&mut hashes2, self.metadata.size, &self.path, &other.path)
     }
 }
+
+/// Compares contents of two files, reading them only until the first difference.
+///
+/// Files of different sizes are never read, and hardlinks of the same file are always identical.
+pub fn files_identical(a: impl AsRef<Path>, b: impl AsRef<Path>) -> io::Result<bool> {
+    let (a, b) = (a.as_ref(), b.as_ref());
+    let (a_meta, b_meta) = (fs::metadata(a)?, fs::metadata(b)?);
+    if a_meta.len() != b_meta.len() {
+        return Ok(false);
+    }
+    if a_meta.dev() == b_meta.dev() && a_meta.ino() == b_meta.ino() {
+        return Ok(true);
+    }
+    let ord = Hasher::new().compare(&mut Hasher::new(), a_meta.len(), a, b)?;
+    Ok(ord == Ordering::Equal)
+}
+
+#[test]
+fn files_identical_test() {
+    let dir = tempdir::TempDir::new("identicaltest").unwrap();
+    let path = |name| dir.path().join(name);
+    fs::write(path("a"), "same").unwrap();
+    fs::write(path("b"), "same").unwrap();
+    fs::write(path("c"), "diff").unwrap();
+    fs::write(path("d"), "longer").unwrap();
+    assert!(files_identical(path("a"), path("b")).unwrap());
+    assert!(files_identical(path("a"), path("a")).unwrap());
+    assert!(!files_identical(path("a"), path("c")).unwrap());
+    assert!(!files_identical(path("a"), path("d")).unwrap());
+    assert!(files_identical(path("a"), path("missing")).is_err());
+}
@@ -1,4 +1,4 @@
-use crate::lazyfile::LazyFile;
+use crate::lazyfile::{self, LazyFile, ReadOptions};
 use smallvec::SmallVec;
 use std::cmp::{min, Ordering};
 use std::convert::TryInto;
@@ -14,8 +14,9 @@ struct HashedRange {
 }
 
 impl HashedRange {
-    pub fn from_file(file: &mut LazyFile<'_>, start: u64, size: u64) -> Result<Self, io::Error> {
-        let fd = file.fd()?;
+    /// If `full` is given, the data is also added to it
+    pub fn from_file(file: &mut LazyFile<'_>, start: u64, size: u64, mut full: Option<&mut blake3::Hasher>) -> Result<Self, io::Error> {
+        let mut fd = file.fd()?;
         fd.seek(SeekFrom::Start(start))?;
         let mut hasher = blake3::Hasher::new();
         let mut to_read = size as usize;
@@ -25,7 +26,11 @@ impl HashedRange {
                 Ok(0) => break,
                 Ok(n) => {
                     debug_assert!(n <= to_read);
+                    lazyfile::count_read(n);
                     hasher.update(&data[0..n]);
+                    if let Some(full) = full.as_deref_mut() {
+                        full.update(&data[0..n]);
+                    }
 
                     to_read -= n;
                     if to_read == 0 {
@@ -41,11 +46,79 @@ impl HashedRange {
             size,
         })
     }
+
+    /// Same as `from_file`, for data that has been read already
+    fn from_data(data: &[u8], size: u64) -> Self {
+        HashedRange {
+            hash: blake3::hash(data).as_bytes()[0..20].try_into().unwrap(),
+            size,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Hasher {
     ranges: SmallVec<[Option<HashedRange>; 1]>,
+    /// Hash of the whole file so far, if it's needed. Ranges are read in order, so it's updated as they're hashed.
+    full: Option<Box<blake3::Hasher>>,
+    /// Bytes added to `full`
+    full_len: u64,
+    /// Hash of the whole file, if it's been computed or imported
+    known: Option<blake3::Hash>,
+    read_options: ReadOptions,
+    chunk_sizes: ChunkSizes,
+    /// Has been compared without hashing, so next time it should be hashed to avoid reading it again for every comparison
+    compared_directly: bool,
+}
+
+/// How much of files is read at a time when comparing them. Chunks start small, so that files that differ early
+/// are told apart quickly, and grow for files that are identical so far, so they can be read faster.
+///
+/// Sizes are `u32`, because they're kept for every file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ChunkSizes {
+    /// Size of the first chunk
+    pub initial: u32,
+    /// Every next chunk is this many times bigger than the previous one
+    pub growth: u32,
+    /// Chunks never grow beyond this, which limits how much memory is used for reading
+    pub max: u32,
+    /// Files up to this size are read in one chunk. Reading a bit more than needed is cheaper than many small reads.
+    pub whole_file: u32,
+}
+
+impl Default for ChunkSizes {
+    fn default() -> Self {
+        ChunkSizes {
+            initial: 2048,
+            growth: 16,
+            max: 128 * 1024 * 1024,
+            whole_file: 256 * 1024,
+        }
+    }
+}
+
+impl ChunkSizes {
+    /// For spinning disks, where a seek between two compared files costs more than reading a lot of data
+    pub fn for_hard_disk(self) -> Self {
+        ChunkSizes {
+            initial: self.initial.max(64 * 1024).min(self.max),
+            whole_file: self.whole_file.max(1024 * 1024).min(self.max),
+            ..self
+        }
+    }
+
+    fn first(&self, file_size: u64) -> u64 {
+        if file_size <= u64::from(self.whole_file.min(self.max)) {
+            return file_size;
+        }
+        // Zero-sized chunks would never finish
+        u64::from(self.initial.min(self.max).max(1))
+    }
+
+    fn next(&self, previous: u64) -> u64 {
+        min(previous * u64::from(self.growth.max(1)), u64::from(self.max.max(1)))
+    }
 }
 
 /// Compares two files using hashes by hashing incrementally until the first difference is found
@@ -54,19 +127,21 @@ struct HashIter<'a> {
     pub start_offset: u64,
     pub end_offset: u64,
     next_buffer_size: u64,
+    chunk_sizes: ChunkSizes,
     a_file: LazyFile<'a>,
     b_file: LazyFile<'a>,
 }
 
 impl<'h> HashIter<'h> {
-    pub fn new(size: u64, a_path: &'h Path, b_path: &'h Path) -> Self {
+    pub fn new(size: u64, chunk_sizes: ChunkSizes, a_path: &'h Path, a_options: ReadOptions, b_path: &'h Path, b_options: ReadOptions) -> Self {
         HashIter {
             index: 0,
             start_offset: 0,
             end_offset: size,
-            next_buffer_size: 2048,
-            a_file: LazyFile::new(a_path),
-            b_file: LazyFile::new(b_path),
+            next_buffer_size: chunk_sizes.first(size),
+            chunk_sizes,
+            a_file: LazyFile::new(a_path, a_options),
+            b_file: LazyFile::new(b_path, b_options),
         }
     }
 
@@ -96,10 +171,10 @@ impl<'h> HashIter<'h> {
 
         // If any of the ranges is missing, compute it
         if a_none {
-            a_hash.push(HashedRange::from_file(&mut self.a_file, self.start_offset, size));
+            a_hash.push_from_file(&mut self.a_file, self.start_offset, size);
         }
         if b_none {
-            b_hash.push(HashedRange::from_file(&mut self.b_file, self.start_offset, size));
+            b_hash.push_from_file(&mut self.b_file, self.start_offset, size);
         }
 
         self.index += 1;
@@ -108,7 +183,12 @@ impl<'h> HashIter<'h> {
         // and reading files one by one without trashing.
         // Exponential increase is meant to be a compromise that allows finding
         // the difference in the first few KB, but grow quickly to read identical files faster.
-        self.next_buffer_size = min(size * 16, 128 * 1024 * 1024);
+        self.next_buffer_size = self.chunk_sizes.next(size);
+        if self.start_offset < self.end_offset {
+            let next_size = min(self.end_offset - self.start_offset, self.next_buffer_size);
+            self.a_file.prefetch(self.start_offset, next_size);
+            self.b_file.prefetch(self.start_offset, next_size);
+        }
 
         match (a_hash.ranges.get(i), b_hash.ranges.get(i)) {
             (Some(Some(a)), Some(Some(b))) => Ok(Some((a, b))),
@@ -122,9 +202,53 @@ impl Hasher {
     pub fn new() -> Self {
         Hasher {
             ranges: SmallVec::new(),
+            full: None,
+            full_len: 0,
+            known: None,
+            read_options: ReadOptions::default(),
+            chunk_sizes: ChunkSizes::default(),
+            compared_directly: false,
+        }
+    }
+
+    /// Also keeps a hash of the whole file, for `full_hash()`
+    pub fn with_full_hash() -> Self {
+        Hasher {
+            full: Some(Box::default()),
+            ..Self::new()
+        }
+    }
+
+    /// Uses a hash of the whole file from elsewhere, e.g. a manifest
+    pub fn with_known_hash(hash: blake3::Hash) -> Self {
+        Hasher {
+            known: Some(hash),
+            ..Self::new()
         }
     }
 
+    pub fn set_read_options(&mut self, options: ReadOptions) {
+        self.read_options = options;
+    }
+
+    pub fn set_chunk_sizes(&mut self, chunk_sizes: ChunkSizes) {
+        self.chunk_sizes = chunk_sizes;
+    }
+
+    fn push_from_file(&mut self, file: &mut LazyFile<'_>, start: u64, size: u64) {
+        let full = self.full.as_deref_mut().filter(|_| self.full_len == start);
+        let keeps_full = full.is_some();
+        let range = HashedRange::from_file(file, start, size, full);
+        if keeps_full {
+            match range {
+                Ok(_) => self.full_len += size,
+                // The full hash may have got a partial range
+                Err(_) => self.full = None,
+            }
+        }
+        self.push(range);
+    }
+
     #[inline]
     fn push(&mut self, range: Result<HashedRange, io::Error>) {
         let r = match range {
@@ -140,7 +264,13 @@ impl Hasher {
     /// Incremental comparison reading files lazily
     #[inline]
     pub fn compare(&mut self, other: &mut Hasher, size: u64, self_path: &Path, other_path: &Path) -> Result<Ordering, io::Error> {
-        let mut iter = HashIter::new(size, self_path, other_path);
+        if self.can_compare_directly() && other.can_compare_directly() {
+            self.compared_directly = true;
+            other.compared_directly = true;
+            return self.compare_directly(other, size, self_path, other_path);
+        }
+
+        let mut iter = HashIter::new(size, self.chunk_sizes, self_path, self.read_options, other_path, other.read_options);
 
         while let Some((a, b)) = iter.next(self, other)? {
             let ord = a.cmp(b);
@@ -150,6 +280,90 @@ impl Hasher {
         }
         Ok(Ordering::Equal)
     }
+
+    /// Files that have never been compared are usually a pair of the only two files of their size.
+    /// If there are more, hashes are needed, so that every file is read only once.
+    fn can_compare_directly(&self) -> bool {
+        !self.compared_directly && self.ranges.is_empty() && self.full.is_none() && self.known.is_none()
+    }
+
+    /// Compares bytes instead of hashes, which is faster for identical files. The order is the same as if they were compared by hashes,
+    /// since only the first chunk that differs is hashed.
+    fn compare_directly(&self, other: &Hasher, size: u64, self_path: &Path, other_path: &Path) -> Result<Ordering, io::Error> {
+        let mut a_file = LazyFile::new(self_path, self.read_options);
+        let mut b_file = LazyFile::new(other_path, other.read_options);
+        let mut chunk_size = self.chunk_sizes.first(size);
+        let (mut a_data, mut b_data) = (Vec::new(), Vec::new());
+        let mut start = 0;
+        while start < size {
+            let chunk_size_now = min(size - start, chunk_size);
+            let a_len = read_chunk(&mut a_file, start, chunk_size_now, &mut a_data)?;
+            let b_len = read_chunk(&mut b_file, start, chunk_size_now, &mut b_data)?;
+            if a_data[..a_len] != b_data[..b_len] {
+                let a = HashedRange::from_data(&a_data[..a_len], chunk_size_now);
+                let b = HashedRange::from_data(&b_data[..b_len], chunk_size_now);
+                // Different data could have the same hash only if BLAKE3 was broken
+                return Ok(a.cmp(&b));
+            }
+            start += chunk_size_now;
+            chunk_size = self.chunk_sizes.next(chunk_size_now);
+        }
+        Ok(Ordering::Equal)
+    }
+
+    /// Hash of the whole file. Only reads what hasn't been hashed for comparisons already.
+    pub fn full_hash(&self, path: &Path) -> Result<blake3::Hash, io::Error> {
+        if let Some(known) = self.known {
+            return Ok(known);
+        }
+        let (mut hasher, start) = match &self.full {
+            Some(full) => ((**full).clone(), self.full_len),
+            None => (blake3::Hasher::new(), 0),
+        };
+        let mut file = LazyFile::new(path, self.read_options);
+        let mut file = file.fd()?;
+        file.seek(SeekFrom::Start(start))?;
+        let mut data = vec![0; 1 << 16];
+        loop {
+            match file.read(&mut data) {
+                Ok(0) => break,
+                Ok(n) => {
+                    lazyfile::count_read(n);
+                    hasher.update(&data[0..n]);
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(hasher.finalize())
+    }
+
+    /// Same as `full_hash`, but keeps the result for next time
+    pub fn cached_full_hash(&mut self, path: &Path) -> Result<blake3::Hash, io::Error> {
+        let hash = self.full_hash(path)?;
+        self.known = Some(hash);
+        Ok(hash)
+    }
+}
+
+/// Reads `size` bytes from `start`, or less if the file is shorter. Returns how many bytes have been read into `data`.
+fn read_chunk(file: &mut LazyFile<'_>, start: u64, size: u64, data: &mut Vec<u8>) -> Result<usize, io::Error> {
+    let mut fd = file.fd()?;
+    fd.seek(SeekFrom::Start(start))?;
+    data.resize(size as usize, 0);
+    let mut len = 0;
+    while len < data.len() {
+        match fd.read(&mut data[len..]) {
+            Ok(0) => break,
+            Ok(n) => {
+                lazyfile::count_read(n);
+                len += n;
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
 }
 
 #[cfg(test)]
@@ -163,13 +377,51 @@ mod test {
         let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
         let path = &tmp.path().join("a");
         fs::write(path, "aaa\n").expect("write");
-        let mut file = LazyFile::new(path);
-        let hashed = HashedRange::from_file(&mut file, 0, 4).expect("hash");
+        let mut file = LazyFile::new(path, ReadOptions::default());
+        let hashed = HashedRange::from_file(&mut file, 0, 4, None).expect("hash");
 
         assert_eq!(4, hashed.size);
         assert_eq!([22, 179, 164, 66, 194, 34, 185, 88, 69, 62, 115, 203, 129, 138, 81, 160, 96, 190, 209, 11], hashed.hash);
 
-        let hashed = HashedRange::from_file(&mut file, 1, 2).expect("hash2");
+        let hashed = HashedRange::from_file(&mut file, 1, 2, None).expect("hash2");
         assert_eq!(2, hashed.size);
     }
+
+    #[test]
+    fn small_chunks() {
+        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
+        let (a, b) = (tmp.path().join("a"), tmp.path().join("b"));
+        fs::write(&a, "0123456789abcdef").expect("write");
+        fs::write(&b, "0123456789abcdeX").expect("write");
+        let chunk_sizes = ChunkSizes { initial: 1, growth: 2, max: 4, whole_file: 0 };
+        let (mut a_hash, mut b_hash) = (Hasher::new(), Hasher::new());
+        a_hash.set_chunk_sizes(chunk_sizes);
+        b_hash.set_chunk_sizes(chunk_sizes);
+        let direct = a_hash.compare(&mut b_hash, 16, &a, &b).expect("cmp");
+        assert_ne!(Ordering::Equal, direct);
+        assert!(a_hash.ranges.is_empty());
+        // Second time it's hashed, and must be in the same order
+        assert_eq!(direct, a_hash.compare(&mut b_hash, 16, &a, &b).expect("cmp"));
+        // 1, 2, 4, 4, 4, 1
+        assert_eq!(6, a_hash.ranges.len());
+        let mut same = Hasher::new();
+        same.set_chunk_sizes(chunk_sizes);
+        assert_eq!(Ordering::Equal, a_hash.compare(&mut same, 16, &a, &a).expect("cmp"));
+    }
+
+    #[test]
+    fn whole_file_chunk() {
+        let chunk_sizes = ChunkSizes::default();
+        assert_eq!(1000, chunk_sizes.first(1000));
+        assert_eq!(2048, chunk_sizes.first(10_000_000));
+        assert_eq!(4, ChunkSizes { max: 4, ..chunk_sizes }.first(1000));
+    }
+
+    #[test]
+    fn hard_disk_chunks() {
+        let chunk_sizes = ChunkSizes::default().for_hard_disk();
+        assert_eq!(1_000_000, chunk_sizes.first(1_000_000));
+        assert_eq!(64 * 1024, chunk_sizes.first(10_000_000));
+        assert_eq!(4, ChunkSizes { max: 4, ..ChunkSizes::default() }.for_hard_disk().first(1000));
+    }
 }
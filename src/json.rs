@@ -1,16 +1,36 @@
+use crate::file::{FileContent, FileSet};
+use crate::scanner::RunMode;
 use crate::scanner::ScanListener;
 use crate::scanner::Scanner;
 use crate::scanner::Stats;
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde::ser::Serializer;
 use serde_derive::*;
-use std::path::Path;
+use std::cell::RefCell;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::ops::Deref;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 #[derive(Debug)]
-pub struct JsonOutput;
+pub struct JsonOutput {
+    /// Printed to stdout if `None`
+    path: Option<PathBuf>,
+}
 
 impl JsonOutput {
     pub fn new() -> Self {
-        JsonOutput
+        JsonOutput { path: None }
+    }
+
+    /// Writes the report to a file instead of stdout, so that it can be combined with other outputs
+    pub fn to_file(path: impl Into<PathBuf>) -> Self {
+        JsonOutput { path: Some(path.into()) }
     }
 }
 
@@ -20,9 +40,30 @@ impl ScanListener for JsonOutput {
     }
 
     fn scan_over(&self, scanner: &Scanner, stats: &Stats, scan_duration: Duration) {
-        let data = JsonSerializable::new(scanner, stats, scan_duration);
-        let json_string = serde_json::to_string_pretty(&data).unwrap();
-        println!("{}", json_string);
+        // Groups are written as they're converted, so that they don't have to be all copied at once
+        let groups = RefCell::new(Vec::new());
+        let report = StreamedReport {
+            format_version: FORMAT_VERSION,
+            creator: creator(),
+            dupes: StreamedDupes { scanner, groups: &groups },
+            groups: &groups,
+            duplicate_dirs: duplicate_dirs(scanner),
+            hardlinks: hardlinks(scanner),
+            roots: scanner.roots().iter().map(|root| json_path(scanner, root)).collect(),
+            stats,
+            scan_duration,
+        };
+        match &self.path {
+            Some(path) => {
+                let res = fs::File::create(path).and_then(|file| write_report(io::BufWriter::new(file), &report));
+                if let Err(err) = res {
+                    eprintln!("Can't write {}: {}", path.display(), err);
+                }
+            },
+            None => if let Err(err) = write_report(io::stdout().lock(), &report) {
+                eprintln!("Can't write the report: {}", err);
+            },
+        }
     }
 
     fn hardlinked(&mut self, _: &Path, _: &Path) {
@@ -34,32 +75,282 @@ impl ScanListener for JsonOutput {
     }
 }
 
+/// Incremented whenever existing keys change their meaning or shape. New keys may be added without changing it.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The report printed by `--json`, which can also be read back.
+///
+/// Key names are stable. Fields added later are optional when reading older reports.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JsonReport {
+    /// `FORMAT_VERSION` of the program that made the report. Reports from before it was added have 0.
+    #[serde(default)]
+    pub format_version: u32,
+    pub creator: String,
+    /// Groups of identical files. Each group has sets of paths that are hardlinks of each other.
+    pub dupes: Vec<Vec<Vec<JsonPath>>>,
+    /// Information about each group in `dupes`, in the same order
+    #[serde(default)]
+    pub groups: Vec<JsonGroup>,
+    /// Groups of directories with identical contents, if enabled with `Settings::find_duplicate_dirs`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub duplicate_dirs: Vec<Vec<JsonPath>>,
+    /// Groups of files that are hardlinks of each other, if enabled with `Settings::report_hardlinks`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hardlinks: Vec<Vec<JsonPath>>,
+    /// Paths as given to the scanner. `stats.by_root` is in the same order.
+    #[serde(default)]
+    pub roots: Vec<JsonPath>,
+    pub stats: Stats,
+    pub scan_duration: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JsonGroup {
+    /// Size of each file in bytes
+    pub size: u64,
+    /// `st_dev` of the filesystem the files are on
+    pub device: u64,
+    /// How the files have been deduplicated
+    pub link: JsonLink,
+    /// Number of separate copies (not counting hardlinks) that have been found, before they've been merged
+    #[serde(default)]
+    pub copies: usize,
+    /// Bytes freed by merging the copies, or that would be freed in a dry run
+    #[serde(default)]
+    pub bytes_saved: u64,
+    /// BLAKE3 hash of the content, in hex. It's the same for the same content in any report, so groups can be matched between reports.
+    /// `None` in reports from older versions, or if none of the files could be read.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blake3: Option<String>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JsonLink {
+    /// Not changed (e.g. in a dry run)
+    None,
+    /// All files in the group are hardlinks of one file
+    Hardlink,
+}
+
+impl JsonReport {
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = io::BufReader::new(fs::File::open(path)?);
+        serde_json::from_reader(file).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// Writes it in the same format as `JsonOutput`
+    pub fn write(&self, mut out: impl io::Write) -> io::Result<()> {
+        serde_json::to_writer_pretty(&mut out, self)?;
+        writeln!(out)?;
+        out.flush()
+    }
+
+    pub fn new(scanner: &Scanner, stats: &Stats, scan_duration: Duration) -> Self {
+        let (dupes, groups) = scanner.dupes_with_content_iter().filter_map(|(content, sets)| json_group(scanner, content, sets)).unzip();
+        JsonReport {
+            format_version: FORMAT_VERSION,
+            creator: creator(),
+            dupes,
+            groups,
+            duplicate_dirs: duplicate_dirs(scanner),
+            hardlinks: hardlinks(scanner),
+            roots: scanner.roots().iter().map(|root| json_path(scanner, root)).collect(),
+            stats: stats.clone(),
+            scan_duration,
+        }
+    }
+}
+
+/// Same keys as `JsonReport`, but without a copy of all the paths
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
-struct JsonSerializable {
+struct StreamedReport<'a> {
+    format_version: u32,
     creator: String,
-    dupes: Vec<Vec<Vec<Box<Path>>>>,
-    stats: Stats,
+    dupes: StreamedDupes<'a>,
+    /// Filled in while `dupes` are written
+    groups: &'a RefCell<Vec<JsonGroup>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    duplicate_dirs: Vec<Vec<JsonPath>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    hardlinks: Vec<Vec<JsonPath>>,
+    roots: Vec<JsonPath>,
+    stats: &'a Stats,
     scan_duration: Duration,
 }
 
-impl JsonSerializable {
-    pub fn new(scanner: &Scanner, stats: &Stats, scan_duration: Duration) -> Self {
-        JsonSerializable {
-            creator: format!("duplicate-kriller {}", env!("CARGO_PKG_VERSION")),
-            dupes: scanner
-                .dupes()
-                .into_iter()
-                .map(|sets| {
-                    sets.into_iter()
-                        .filter(|set| !set.paths.is_empty())
-                        .map(|set| set.paths.into_vec())
-                        .collect::<Vec<_>>()
-                })
-                .filter(|sets| sets.len() > 1 || sets.iter().any(|set| set.len() > 1))
-                .collect(),
-            stats: *stats,
-            scan_duration,
+/// Writes `dupes` group by group. Information for `groups` is small, so it's kept until all `dupes` have been written.
+struct StreamedDupes<'a> {
+    scanner: &'a Scanner,
+    groups: &'a RefCell<Vec<JsonGroup>>,
+}
+
+impl serde::Serialize for StreamedDupes<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let StreamedDupes { scanner, groups } = self;
+        serializer.collect_seq(scanner.dupes_with_content_iter().filter_map(|(content, sets)| json_group(scanner, content, sets)).map(|(sets, group)| {
+            groups.borrow_mut().push(group);
+            sets
+        }))
+    }
+}
+
+fn write_report(mut out: impl io::Write, report: &StreamedReport<'_>) -> io::Result<()> {
+    serde_json::to_writer_pretty(&mut out, report)?;
+    writeln!(out)?;
+    out.flush()
+}
+
+pub(crate) fn creator() -> String {
+    format!("duplicate-kriller {}", env!("CARGO_PKG_VERSION"))
+}
+
+fn duplicate_dirs(scanner: &Scanner) -> Vec<Vec<JsonPath>> {
+    if !scanner.settings.find_duplicate_dirs {
+        return Vec::new();
+    }
+    scanner.duplicate_dirs().into_iter()
+        .map(|group| group.iter().map(|dir| json_path(scanner, dir)).collect())
+        .collect()
+}
+
+fn hardlinks(scanner: &Scanner) -> Vec<Vec<JsonPath>> {
+    if !scanner.settings.report_hardlinks {
+        return Vec::new();
+    }
+    scanner.hardlink_groups().into_iter()
+        .map(|set| set.paths.iter().map(|path| json_path(scanner, path)).collect())
+        .collect()
+}
+
+/// Reports can be used from another directory (e.g. by `apply`), so relative paths are made absolute
+fn json_path(scanner: &Scanner, path: &Path) -> JsonPath {
+    JsonPath(scanner.absolute(path).into_boxed_path())
+}
+
+/// Paths of a group from `Scanner::dupes()`, unless there's nothing to report about it
+fn json_group(scanner: &Scanner, content: Option<&FileContent>, sets: Vec<FileSet>) -> Option<(Vec<Vec<JsonPath>>, JsonGroup)> {
+    // Merged sets are left empty
+    let copies = sets.len();
+    let sets: Vec<Vec<JsonPath>> = sets.into_iter()
+        .filter(|set| !set.paths.is_empty())
+        .map(|set| set.paths.iter().map(|path| json_path(scanner, path)).collect())
+        .collect();
+    if sets.len() < 2 && sets.iter().all(|set| set.len() < 2) {
+        return None;
+    }
+    let metadata = sets.first().and_then(|set| set.first()).and_then(|path| fs::symlink_metadata(path).ok());
+    let size = metadata.as_ref().map_or(0, |m| m.len());
+    // Without merging, every set would be linked to the first one
+    let replaced = if scanner.settings.run_mode == RunMode::DryRunNoMerging { sets.len() - 1 } else { copies - sets.len() };
+    let group = JsonGroup {
+        size,
+        device: metadata.as_ref().map_or(0, |m| m.dev()),
+        link: if sets.len() == 1 && scanner.settings.run_mode == RunMode::Hardlink { JsonLink::Hardlink } else { JsonLink::None },
+        copies,
+        bytes_saved: size * replaced as u64,
+        blake3: content_hash(content, sets.iter().flatten().map(|path| &**path)).map(|hash| hash.to_hex().to_string()),
+    };
+    Some((sets, group))
+}
+
+/// Computed only when the report is written. With `Settings::full_hashes` the compared files have been hashed already, otherwise one file of the group is read.
+/// The first file of the group could have been replaced since, so the others are tried too.
+pub(crate) fn content_hash<'a>(content: Option<&FileContent>, mut paths: impl Iterator<Item = &'a Path>) -> Option<blake3::Hash> {
+    content.and_then(|content| content.full_hash().ok()).or_else(|| {
+        paths.find_map(|path| FileContent::from_path(path.into()).and_then(|content| content.full_hash()).ok())
+    })
+}
+
+/// A path that is a string in JSON if it's valid UTF-8, or an array of bytes otherwise.
+/// Unlike serde's default, it doesn't fail or lose information on non-UTF-8 paths.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct JsonPath(pub Box<Path>);
+
+impl Deref for JsonPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for JsonPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl serde::Serialize for JsonPath {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0.to_str() {
+            Some(s) => serializer.serialize_str(s),
+            None => serializer.serialize_bytes(self.0.as_os_str().as_bytes()),
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for JsonPath {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(JsonPathVisitor)
+    }
+}
+
+struct JsonPathVisitor;
+
+impl<'de> Visitor<'de> for JsonPathVisitor {
+    type Value = JsonPath;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a path string or an array of bytes")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<JsonPath, E> {
+        Ok(JsonPath(Path::new(v).into()))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<JsonPath, E> {
+        Ok(JsonPath(Path::new(OsStr::from_bytes(v)).into()))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<JsonPath, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(b) = seq.next_element::<u8>()? {
+            bytes.push(b);
         }
+        Ok(JsonPath(PathBuf::from(OsString::from_vec(bytes)).into_boxed_path()))
     }
 }
+
+#[test]
+fn report_groups_test() {
+    use crate::scanner::Scanner;
+
+    let dir = tempdir::TempDir::new("jsontest").unwrap();
+    for name in ["a", "b", "c"] {
+        fs::write(dir.path().join(name), "same").unwrap();
+    }
+    let mut s = Scanner::new();
+    s.settings.ignore_small = false;
+    s.settings.run_mode = RunMode::DryRun;
+    s.scan(dir.path()).unwrap();
+    let report = JsonReport::new(&s, &Stats::default(), Duration::ZERO);
+    assert_eq!(1, report.groups.len());
+    assert_eq!((3, 8), (report.groups[0].copies, report.groups[0].bytes_saved));
+    assert_eq!(JsonLink::None, report.groups[0].link);
+    assert_eq!(Some(blake3::hash(b"same").to_hex().to_string()), report.groups[0].blake3);
+
+    // `-d` doesn't merge the sets, but reports what linking would save
+    let mut s = Scanner::new();
+    s.settings.ignore_small = false;
+    s.settings.run_mode = RunMode::DryRunNoMerging;
+    s.scan(dir.path()).unwrap();
+    let report = JsonReport::new(&s, &Stats::default(), Duration::ZERO);
+    assert_eq!(1, report.groups.len());
+    assert_eq!(3, report.dupes[0].len());
+    assert_eq!((3, 8), (report.groups[0].copies, report.groups[0].bytes_saved));
+}
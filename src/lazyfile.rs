@@ -1,30 +1,194 @@
+use std::cell::{Cell, RefCell, RefMut};
 use std::fs;
 use std::io;
 use std::path::Path;
+use std::rc::{Rc, Weak};
+
+/// How files are opened for comparing
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct ReadOptions {
+    /// Don't update the file's access time. Only works on Linux, and for files the user owns.
+    pub no_atime: bool,
+    /// Evict the file from the page cache after reading it, so that scanning doesn't push out other programs' data
+    pub drop_cache: bool,
+    /// While a chunk is compared, ask the OS to start reading the next one, so that disks can keep streaming
+    pub prefetch: bool,
+}
+
+type SharedFile = Rc<RefCell<Option<fs::File>>>;
+
+thread_local! {
+    /// Files opened by all `LazyFile`s, least recently used first. They can be closed when running out of file descriptors.
+    static OPEN_FILES: RefCell<Vec<Weak<RefCell<Option<fs::File>>>>> = const { RefCell::new(Vec::new()) };
+    /// Total of `count_read()` on this thread
+    static BYTES_READ: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Should be called for all data read from `LazyFile`s, so that the scanner can tell how much it had to read
+pub(crate) fn count_read(bytes: usize) {
+    BYTES_READ.with(|total| total.set(total.get() + bytes as u64));
+}
+
+/// Bytes read from files on this thread so far
+pub(crate) fn bytes_read() -> u64 {
+    BYTES_READ.with(Cell::get)
+}
 
 /// Open the file only if necessary.
-/// The file will be closed automatically when this object goes out of scope.
+/// The file will be closed automatically when this object goes out of scope,
+/// or earlier if other files need the descriptor (in which case it's reopened on next use).
 pub struct LazyFile<'a> {
     path: &'a Path,
-    file: Option<fs::File>,
+    options: ReadOptions,
+    file: SharedFile,
 }
 
 impl<'a> LazyFile<'a> {
-    pub fn new(path: &'a Path) -> Self {
-        LazyFile { path, file: None }
+    pub fn new(path: &'a Path, options: ReadOptions) -> Self {
+        LazyFile { path, options, file: Rc::new(RefCell::new(None)) }
+    }
+
+    /// Open the file (or reuse already-opened handle). Callers must seek before reading, since a reopened file starts at 0.
+    pub fn fd(&mut self) -> Result<RefMut<'_, fs::File>, io::Error> {
+        if self.file.borrow().is_none() {
+            let file = open_retrying(self.path, self.options)?;
+            *self.file.borrow_mut() = Some(file);
+        }
+        mark_used(&self.file);
+        Ok(RefMut::map(self.file.borrow_mut(), |file| file.as_mut().expect("just opened")))
     }
 
-    /// Open the file (or reuse already-opened handle)
-    pub fn fd(&mut self) -> Result<&mut fs::File, io::Error> {
-        if let Some(ref mut fd) = self.file {
-            Ok(fd)
-        } else {
-            self.file = Some(fs::File::open(self.path)?);
-            if let Some(ref mut fd) = self.file {
-                Ok(fd)
-            } else {
-                unreachable!();
-            }
+    /// Starts reading the range in the background, if the file is open and prefetching is enabled
+    pub fn prefetch(&self, start: u64, len: u64) {
+        if let (Some(file), true) = (&*self.file.borrow(), self.options.prefetch) {
+            advise(file, start, len, Advice::WillNeed);
         }
     }
 }
+
+impl Drop for LazyFile<'_> {
+    fn drop(&mut self) {
+        if let (Some(file), true) = (&*self.file.borrow(), self.options.drop_cache) {
+            advise(file, 0, 0, Advice::DontNeed);
+        }
+    }
+}
+
+/// Moves the file to the end of the LRU list
+fn mark_used(file: &SharedFile) {
+    OPEN_FILES.with(|open| {
+        let mut open = open.borrow_mut();
+        // Also forgets files of dropped `LazyFile`s
+        open.retain(|f| f.strong_count() > 0 && !std::ptr::eq(f.as_ptr(), Rc::as_ptr(file)));
+        open.push(Rc::downgrade(file));
+    });
+}
+
+/// Closes the least recently used file that isn't being read from at the moment. Returns `false` if there was none.
+fn close_least_recently_used() -> bool {
+    OPEN_FILES.with(|open| {
+        let mut open = open.borrow_mut();
+        let closable = open.iter().position(|f| {
+            f.upgrade().map_or(false, |f| f.try_borrow_mut().map_or(false, |mut f| f.take().is_some()))
+        });
+        match closable {
+            Some(pos) => {
+                open.remove(pos);
+                true
+            },
+            None => false,
+        }
+    })
+}
+
+/// Too many open files is retried after closing other `LazyFile`s, instead of failing the comparison
+fn open_retrying(path: &Path, options: ReadOptions) -> io::Result<fs::File> {
+    loop {
+        match open(path, options) {
+            Err(err) if is_out_of_fds(&err) && close_least_recently_used() => continue,
+            res => return res,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_out_of_fds(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE))
+}
+
+#[cfg(not(unix))]
+fn is_out_of_fds(_: &io::Error) -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+fn open(path: &Path, options: ReadOptions) -> io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = None;
+    if options.no_atime {
+        // Not allowed for files owned by other users (without CAP_FOWNER)
+        match fs::OpenOptions::new().read(true).custom_flags(libc::O_NOATIME).open(path) {
+            Err(err) if err.raw_os_error() == Some(libc::EPERM) => {},
+            res => file = Some(res?),
+        }
+    }
+    let file = match file {
+        Some(file) => file,
+        None => fs::File::open(path)?,
+    };
+    // Files are always read from start to end, so larger readahead helps
+    advise(&file, 0, 0, Advice::Sequential);
+    Ok(file)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open(path: &Path, _: ReadOptions) -> io::Result<fs::File> {
+    fs::File::open(path)
+}
+
+enum Advice {
+    Sequential,
+    WillNeed,
+    DontNeed,
+}
+
+#[cfg(target_os = "linux")]
+fn advise(file: &fs::File, start: u64, len: u64, advice: Advice) {
+    use std::os::unix::io::AsRawFd;
+
+    let advice = match advice {
+        Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+        Advice::WillNeed => libc::POSIX_FADV_WILLNEED,
+        Advice::DontNeed => libc::POSIX_FADV_DONTNEED,
+    };
+    // It's only advice, so errors don't matter
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), start as libc::off_t, len as libc::off_t, advice);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn advise(_: &fs::File, _: u64, _: u64, _: Advice) {}
+
+#[test]
+fn reopen_closed_test() {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let dir = tempdir::TempDir::new("lazytest").unwrap();
+    let path = dir.path().join("a");
+    fs::write(&path, "abc").unwrap();
+    let mut a = LazyFile::new(&path, ReadOptions::default());
+    let mut b = LazyFile::new(&path, ReadOptions::default());
+    a.fd().unwrap();
+    b.fd().unwrap();
+    assert!(close_least_recently_used());
+    assert!(a.file.borrow().is_none());
+    assert!(b.file.borrow().is_some());
+
+    let mut fd = a.fd().unwrap();
+    fd.seek(SeekFrom::Start(1)).unwrap();
+    let mut s = String::new();
+    fd.read_to_string(&mut s).unwrap();
+    assert_eq!("bc", s);
+}
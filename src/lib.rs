@@ -1,15 +1,78 @@
+mod access;
+mod acl;
+mod checkpoint;
+mod compare;
+mod dirs;
+pub mod display;
+mod exclude;
+mod exec;
+mod fdupes;
 mod file;
 mod hasher;
 #[cfg(feature = "json")]
 mod json;
 mod lazyfile;
+mod lock;
+mod log;
+mod manifest;
+#[cfg(feature = "json")]
+mod merge;
 mod metadata;
+mod remote;
+#[cfg(feature = "json")]
+mod rmlint;
 mod scanner;
+mod script;
+mod snapshot;
+#[cfg(feature = "json")]
+mod summary;
+mod systemd;
+mod temp;
+mod trash;
 mod ui;
+mod undo;
+#[cfg(feature = "json")]
+mod verify;
 
-pub use crate::file::FileContent;
+pub use crate::access::{AccessConflict, FileAccess};
+pub use crate::checkpoint::Checkpoint;
+pub use crate::compare::CompareOutput;
+pub use crate::dirs::SimilarDirs;
+pub use crate::exclude::ExcludeRules;
+pub use crate::exec::ExecHook;
+pub use crate::fdupes::FdupesOutput;
+pub use crate::file::{files_identical, FileContent};
+pub use crate::hasher::ChunkSizes;
 #[cfg(feature = "json")]
 pub use crate::json::JsonOutput;
+#[cfg(feature = "json")]
+pub use crate::json::{JsonGroup, JsonLink, JsonPath, JsonReport, FORMAT_VERSION};
+pub use crate::lazyfile::ReadOptions;
+pub use crate::lock::LockFile;
+pub use crate::log::LogFile;
+pub use crate::manifest::{HashExport, HashManifest};
+#[cfg(feature = "json")]
+pub use crate::merge::merge_reports;
+pub use crate::metadata::ExtendedMetadata;
+pub use crate::remote::remote_hashes;
+#[cfg(feature = "json")]
+pub use crate::rmlint::RmlintOutput;
+pub use crate::scanner::Decision;
+pub use crate::scanner::FileFilter;
+pub use crate::scanner::Keep;
+pub use crate::scanner::LinkedMtime;
+pub use crate::scanner::MultiListener;
+pub use crate::scanner::Phase;
 pub use crate::scanner::RunMode;
 pub use crate::scanner::Scanner;
+pub use crate::scanner::{ScanListener, SkipReason};
+pub use crate::scanner::{PartialStats, SkippedStats, Stats};
+pub use crate::script::ShellScriptOutput;
+#[cfg(feature = "json")]
+pub use crate::summary::{ReportSummary, TopGroup};
+pub use crate::systemd::SystemdNotify;
+pub use crate::ui::ColorMode;
 pub use crate::ui::UI as TextUserInterface;
+pub use crate::undo::{undo, UndoLog, UndoStats};
+#[cfg(feature = "json")]
+pub use crate::verify::{verify, VerifyStats};
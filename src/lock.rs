@@ -0,0 +1,48 @@
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+/// Exclusive lock held for as long as this object exists, so that scheduled runs on the same directory don't race each other.
+///
+/// It's an advisory `flock`, so it's released automatically even if the process is killed. The file is left in place.
+#[derive(Debug)]
+pub struct LockFile {
+    file: fs::File,
+}
+
+impl LockFile {
+    /// Fails with `WouldBlock` if another process holds the lock
+    pub fn acquire(path: impl AsRef<Path>) -> io::Result<Self> {
+        // Not truncated before it's locked, since the other instance may have written its pid in it
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        lock(&file).map_err(|err| match err.kind() {
+            io::ErrorKind::WouldBlock => io::Error::new(err.kind(), "another instance is already running"),
+            _ => err,
+        })?;
+        let mut lock = LockFile { file };
+        // Only informative, for finding which process holds it
+        lock.file.set_len(0)?;
+        writeln!(lock.file, "{}", std::process::id())?;
+        Ok(lock)
+    }
+}
+
+fn lock(file: &fs::File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[test]
+fn lock_test() {
+    let dir = tempdir::TempDir::new("locktest").unwrap();
+    let path = dir.path().join("lock");
+    let lock = LockFile::acquire(&path).unwrap();
+    assert_eq!(io::ErrorKind::WouldBlock, LockFile::acquire(&path).unwrap_err().kind());
+    drop(lock);
+    LockFile::acquire(&path).unwrap();
+}
@@ -0,0 +1,96 @@
+use crate::scanner::{ScanListener, Scanner, SkipReason, Stats};
+use crate::undo::escape;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Appends a timestamped line for every link, skip, and error to a file, regardless of other outputs.
+///
+/// Lines are tab-separated: time (UTC), event, and paths or details.
+#[derive(Debug)]
+pub struct LogFile {
+    file: fs::File,
+}
+
+impl LogFile {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = fs::OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(LogFile { file })
+    }
+
+    fn log(&self, event: &str, fields: &[&str]) {
+        let mut line = format!("{}\t{}", timestamp(SystemTime::now()), event);
+        for field in fields {
+            line.push('\t');
+            line.push_str(field);
+        }
+        line.push('\n');
+        // Written in one go, so that concurrent runs appending to the same log don't mix up lines
+        if let Err(err) = (&self.file).write_all(line.as_bytes()) {
+            eprintln!("Can't write to the log: {}", err);
+        }
+    }
+}
+
+impl ScanListener for LogFile {
+    fn file_scanned(&mut self, _: &Path, _: &Stats) {}
+
+    fn scan_over(&self, _: &Scanner, stats: &Stats, scan_duration: Duration) {
+        let summary = format!("{} dupes, {} bytes, {} files added, {} skipped, {:.1}s",
+            stats.dupes, stats.bytes_deduplicated, stats.added, stats.skipped, scan_duration.as_secs_f32());
+        self.log("done", &[&summary]);
+    }
+
+    fn hardlinked(&mut self, src: &Path, dst: &Path) {
+        self.log("linked", &[&escape(src), &escape(dst)]);
+    }
+
+    fn duplicate_found(&mut self, src: &Path, dst: &Path) {
+        self.log("dupe", &[&escape(src), &escape(dst)]);
+    }
+
+    fn skipped(&mut self, path: &Path, reason: SkipReason) {
+        self.log("skipped", &[reason.as_str(), &escape(path)]);
+    }
+
+    fn trashed(&mut self, path: &Path, kept: &Path, trash_path: &Path) {
+        self.log("trashed", &[&escape(path), &escape(kept), &escape(trash_path)]);
+    }
+
+    fn duplicate_in_manifest(&mut self, path: &Path, manifest_path: &Path) {
+        self.log("manifest-dupe", &[&escape(manifest_path), &escape(path)]);
+    }
+
+    fn error(&mut self, path: &Path, err: &io::Error) {
+        self.log("error", &[&escape(path), &err.to_string()]);
+    }
+}
+
+/// RFC 3339 time in UTC
+fn timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+
+    // Days to civil date, from http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60)
+}
+
+#[test]
+fn timestamp_test() {
+    assert_eq!("1970-01-01T00:00:00Z", timestamp(UNIX_EPOCH));
+    assert_eq!("2020-09-13T12:26:40Z", timestamp(UNIX_EPOCH + Duration::from_secs(1_600_000_000)));
+    assert_eq!("2000-02-29T00:00:00Z", timestamp(UNIX_EPOCH + Duration::from_secs(951_782_400)));
+    assert_eq!("1999-12-31T23:59:59Z", timestamp(UNIX_EPOCH + Duration::from_secs(946_684_799)));
+}
@@ -0,0 +1,355 @@
+use crate::scanner::{ScanListener, Scanner, Stats};
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const HEADER: &str = "# dupe-krill hashes v1: size mtime mtime_nsec of the file on the next line";
+
+/// Writes hashes of all scanned files at the end, in the same format as `b3sum`.
+/// Sizes and modification times of the files are written in comment lines, so they have to be removed for `b3sum --check`.
+///
+/// Set `Settings::full_hashes` too, otherwise files that have been compared are read again.
+#[derive(Debug)]
+pub struct HashExport {
+    path: PathBuf,
+}
+
+impl HashExport {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        HashExport { path: path.into() }
+    }
+
+    /// `-` is stdout
+    fn write(&self, scanner: &Scanner) -> io::Result<()> {
+        if self.path.as_os_str() == "-" {
+            return scanner.export_hashes(&mut io::stdout().lock());
+        }
+        let mut out = io::BufWriter::new(fs::File::create(&self.path)?);
+        scanner.export_hashes(&mut out)
+    }
+}
+
+impl ScanListener for HashExport {
+    fn file_scanned(&mut self, _: &Path, _: &Stats) {}
+
+    fn hardlinked(&mut self, _: &Path, _: &Path) {}
+
+    fn duplicate_found(&mut self, _: &Path, _: &Path) {}
+
+    fn scan_over(&self, scanner: &Scanner, _: &Stats, _: Duration) {
+        if let Err(err) = self.write(scanner) {
+            eprintln!("Can't write hashes to {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+/// BLAKE3 hashes from files written by `HashExport` or `b3sum`, for `Scanner::import_hashes`.
+///
+/// Paths are used as they're written in the file, so relative paths are relative to the current directory.
+/// Hashes of local files are used only if they still have the size and modification time written by `HashExport`,
+/// and before any file is replaced it's compared with its source byte by byte anyway.
+#[derive(Debug, Default, Clone)]
+pub struct HashManifest {
+    /// Files that still have the same size and modification time as when they were hashed
+    local: HashMap<Box<Path>, blake3::Hash>,
+    /// Files that don't exist here, e.g. because they're on another machine
+    remote: HashMap<blake3::Hash, Vec<Box<Path>>>,
+}
+
+impl HashManifest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Files that have a different size or modification time than written in the manifest are assumed to have changed, and their hashes are ignored.
+    /// Hashes without them (e.g. from `b3sum`) are used only for files that don't exist here.
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_file_remapped(path, &[])
+    }
+
+    /// Same as `from_file`, but paths starting with the first path of a pair are changed to start with the second one instead.
+    /// This allows using hashes made on another machine, where the same files are mounted in a different directory.
+    /// Remapped files are trusted just like the others: only if their size and modification time match, and they're still compared before linking.
+    pub fn from_file_remapped(path: impl AsRef<Path>, remap: &[(PathBuf, PathBuf)]) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut manifest = Self::new();
+        for entry in parse_lines(&fs::read(path)?, path)? {
+            let file_path = remap_path(entry.path, remap);
+            match fs::symlink_metadata(&file_path) {
+                Ok(m) => if m.is_file() && entry.stat.map_or(false, |stat| stat == FileStat::new(&m)) {
+                    manifest.local.insert(file_path.into_boxed_path(), entry.hash);
+                },
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    manifest.remote.entry(entry.hash).or_default().push(file_path.into_boxed_path());
+                },
+                Err(_) => {},
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Hashes of files on another machine, e.g. from `--export-hashes -` run over SSH. None of the files are assumed to be here,
+    /// even if they have the same paths, and their paths are prefixed with `host:` to tell them apart.
+    pub fn from_remote(mut input: impl io::Read, host: &str) -> io::Result<Self> {
+        let mut data = Vec::new();
+        input.read_to_end(&mut data)?;
+        let mut manifest = Self::new();
+        for entry in parse_lines(&data, Path::new(host))? {
+            let mut remote_path = OsString::from(host);
+            remote_path.push(":");
+            remote_path.push(entry.path);
+            manifest.remote.entry(entry.hash).or_default().push(PathBuf::from(remote_path).into_boxed_path());
+        }
+        Ok(manifest)
+    }
+
+    pub fn extend(&mut self, other: HashManifest) {
+        self.local.extend(other.local);
+        for (hash, paths) in other.remote {
+            self.remote.entry(hash).or_default().extend(paths);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.local.is_empty() && self.remote.is_empty()
+    }
+
+    pub(crate) fn hash_of(&self, path: &Path) -> Option<blake3::Hash> {
+        self.local.get(path).copied()
+    }
+
+    /// Files listed in the manifest that aren't on this machine
+    pub(crate) fn remote_paths(&self, hash: &blake3::Hash) -> &[Box<Path>] {
+        self.remote.get(hash).map_or(&[], |paths| &paths[..])
+    }
+
+    pub(crate) fn has_local(&self) -> bool {
+        !self.local.is_empty()
+    }
+
+    pub(crate) fn has_remote(&self) -> bool {
+        !self.remote.is_empty()
+    }
+}
+
+/// Size and modification time of a file when it was hashed
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct FileStat {
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+impl FileStat {
+    fn new(m: &fs::Metadata) -> Self {
+        FileStat { size: m.size(), mtime: m.mtime(), mtime_nsec: m.mtime_nsec() }
+    }
+
+    /// `# <size> <mtime> <mtime_nsec>`
+    fn parse(line: &[u8]) -> Option<Self> {
+        let line = std::str::from_utf8(line.strip_prefix(b"# ")?).ok()?;
+        let mut fields = line.trim_end_matches('\r').split(' ');
+        let stat = FileStat {
+            size: fields.next()?.parse().ok()?,
+            mtime: fields.next()?.parse().ok()?,
+            mtime_nsec: fields.next()?.parse().ok()?,
+        };
+        fields.next().is_none().then_some(stat)
+    }
+}
+
+#[derive(Debug)]
+struct ManifestEntry {
+    hash: blake3::Hash,
+    path: PathBuf,
+    /// From the comment line before it. `b3sum` doesn't write them.
+    stat: Option<FileStat>,
+}
+
+/// Entries of a manifest. Empty lines and other comments are skipped. `source` is only for error messages.
+fn parse_lines(data: &[u8], source: &Path) -> io::Result<Vec<ManifestEntry>> {
+    let mut entries = Vec::new();
+    let mut stat = None;
+    for line in data.split(|&c| c == b'\n').filter(|line| !line.is_empty()) {
+        if line.starts_with(b"#") {
+            stat = FileStat::parse(line);
+            continue;
+        }
+        let (hash, path) = parse_line(line).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("Invalid BLAKE3 hash line in {}: {}", source.display(), String::from_utf8_lossy(line)))
+        })?;
+        entries.push(ManifestEntry { hash, path, stat: stat.take() });
+    }
+    Ok(entries)
+}
+
+fn remap_path(path: PathBuf, remap: &[(PathBuf, PathBuf)]) -> PathBuf {
+    remap.iter()
+        .find_map(|(from, to)| path.strip_prefix(from).ok().map(|rest| to.join(rest)))
+        .unwrap_or(path)
+}
+
+pub(crate) fn write_header(out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out, "{}", HEADER)
+}
+
+/// Comment line for the entry written after it, so that its hash is used only if the file is still the same
+pub(crate) fn write_stat(out: &mut dyn Write, m: &fs::Metadata) -> io::Result<()> {
+    let stat = FileStat::new(m);
+    writeln!(out, "# {} {} {}", stat.size, stat.mtime, stat.mtime_nsec)
+}
+
+/// `<hex hash>  <path>`. Like `b3sum`, paths with a backslash or newline are escaped, and the line starts with a backslash then.
+pub(crate) fn write_line(out: &mut dyn Write, hash: &blake3::Hash, path: &Path) -> io::Result<()> {
+    let path = path.as_os_str().as_bytes();
+    let needs_escaping = path.iter().any(|&c| c == b'\\' || c == b'\n' || c == b'\r');
+    if needs_escaping {
+        out.write_all(b"\\")?;
+    }
+    write!(out, "{}  ", hash.to_hex())?;
+    if needs_escaping {
+        for &c in path {
+            match c {
+                b'\\' => out.write_all(b"\\\\")?,
+                b'\n' => out.write_all(b"\\n")?,
+                b'\r' => out.write_all(b"\\r")?,
+                c => out.write_all(&[c])?,
+            }
+        }
+    } else {
+        out.write_all(path)?;
+    }
+    out.write_all(b"\n")
+}
+
+/// Reverse of `write_line`. Also accepts `sha1sum`-style binary mode lines (`<hash> *<path>`).
+fn parse_line(line: &[u8]) -> Option<(blake3::Hash, PathBuf)> {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let (escaped, line) = match line.strip_prefix(b"\\") {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+    if line.len() < 66 || !(line[64..].starts_with(b"  ") || line[64..].starts_with(b" *")) {
+        return None;
+    }
+    let mut hash = [0u8; 32];
+    for (byte, hex) in hash.iter_mut().zip(line[..64].chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?;
+    }
+    let path = &line[66..];
+    if path.is_empty() {
+        return None;
+    }
+    let path = if escaped {
+        let mut unescaped = Vec::with_capacity(path.len());
+        let mut bytes = path.iter();
+        while let Some(&c) = bytes.next() {
+            unescaped.push(if c == b'\\' {
+                match bytes.next()? {
+                    b'\\' => b'\\',
+                    b'n' => b'\n',
+                    b'r' => b'\r',
+                    _ => return None,
+                }
+            } else {
+                c
+            });
+        }
+        PathBuf::from(OsStr::from_bytes(&unescaped))
+    } else {
+        PathBuf::from(OsStr::from_bytes(path))
+    };
+    Some((blake3::Hash::from(hash), path))
+}
+
+#[test]
+fn write_line_test() {
+    let hash = blake3::hash(b"");
+    let mut out = Vec::new();
+    write_line(&mut out, &hash, Path::new("a/b c")).unwrap();
+    write_line(&mut out, &hash, Path::new("a\\b\nc")).unwrap();
+    let hex = hash.to_hex();
+    assert_eq!(format!("{}  a/b c\n\\{}  a\\\\b\\nc\n", hex, hex), String::from_utf8(out).unwrap());
+}
+
+#[test]
+fn parse_line_test() {
+    let hash = blake3::hash(b"x");
+    for path in ["a/b c", "a\\b\nc\r", " x"] {
+        let mut out = Vec::new();
+        write_line(&mut out, &hash, Path::new(path)).unwrap();
+        assert_eq!(Some((hash, PathBuf::from(path))), parse_line(out.strip_suffix(b"\n").unwrap()));
+    }
+    let binary_mode = format!("{} *foo", hash.to_hex());
+    assert_eq!(Some((hash, PathBuf::from("foo"))), parse_line(binary_mode.as_bytes()));
+    assert_eq!(None, parse_line(b"d41d8cd98f00b204e9800998ecf8427e  md5"));
+    assert_eq!(None, parse_line(format!("{}  ", hash.to_hex()).as_bytes()));
+}
+
+#[test]
+fn remap_test() {
+    let remap = [(PathBuf::from("/mnt/nas"), PathBuf::from("/volume1")), (PathBuf::from("rel"), PathBuf::from("/abs"))];
+    assert_eq!(PathBuf::from("/volume1/a/b"), remap_path("/mnt/nas/a/b".into(), &remap));
+    assert_eq!(PathBuf::from("/abs/c"), remap_path("rel/c".into(), &remap));
+    assert_eq!(PathBuf::from("/mnt/nasty/d"), remap_path("/mnt/nasty/d".into(), &remap));
+}
+
+#[test]
+fn remote_test() {
+    let hash = blake3::hash(b"x");
+    let input = format!("{}  /data/a\n\n{}  /data/b\n", hash.to_hex(), hash.to_hex());
+    let manifest = HashManifest::from_remote(input.as_bytes(), "backup").unwrap();
+    assert_eq!(None, manifest.hash_of(Path::new("/data/a")));
+    assert_eq!(&[Box::from(Path::new("backup:/data/a")), Box::from(Path::new("backup:/data/b"))], manifest.remote_paths(&hash));
+    assert!(HashManifest::from_remote(&b"nope\n"[..], "backup").is_err());
+}
+
+#[test]
+fn stat_test() {
+    let dir = tempdir::TempDir::new("stattest").unwrap();
+    let file = dir.path().join("a");
+    fs::write(&file, "foo").unwrap();
+    let m = fs::metadata(&file).unwrap();
+    let hash = blake3::hash(b"foo");
+    let manifest = |stat: &str| {
+        let manifest_path = dir.path().join("hashes.b3");
+        let mut data = format!("{}\n{}", HEADER, stat).into_bytes();
+        write_line(&mut data, &hash, &file).unwrap();
+        fs::write(&manifest_path, data).unwrap();
+        HashManifest::from_file(&manifest_path).unwrap()
+    };
+    assert_eq!(Some(hash), manifest(&format!("# {} {} {}\n", m.size(), m.mtime(), m.mtime_nsec())).hash_of(&file));
+    // Modified, or copied with the mtime preserved, but with a different size
+    assert_eq!(None, manifest(&format!("# {} {} {}\n", m.size(), m.mtime() - 1, m.mtime_nsec())).hash_of(&file));
+    assert_eq!(None, manifest(&format!("# {} {} {}\n", m.size() + 1, m.mtime(), m.mtime_nsec())).hash_of(&file));
+    // From b3sum
+    let manifest = manifest("");
+    assert_eq!(None, manifest.hash_of(&file));
+    assert!(!manifest.has_remote());
+}
+
+#[test]
+fn remap_stat_test() {
+    let dir = tempdir::TempDir::new("remapstattest").unwrap();
+    let file = dir.path().join("a");
+    fs::write(&file, "foo").unwrap();
+    let m = fs::metadata(&file).unwrap();
+    let hash = blake3::hash(b"foo");
+    let remap = [(PathBuf::from("/mnt/nas"), dir.path().to_path_buf())];
+    let manifest = |stat: &str| {
+        let manifest_path = dir.path().join("hashes.b3");
+        let mut data = stat.as_bytes().to_vec();
+        write_line(&mut data, &hash, Path::new("/mnt/nas/a")).unwrap();
+        fs::write(&manifest_path, data).unwrap();
+        HashManifest::from_file_remapped(&manifest_path, &remap).unwrap()
+    };
+    assert_eq!(Some(hash), manifest(&format!("# {} {} {}\n", m.size(), m.mtime(), m.mtime_nsec())).hash_of(&file));
+    assert_eq!(None, manifest(&format!("# {} {} {}\n", m.size(), m.mtime(), m.mtime_nsec() + 1)).hash_of(&file));
+    assert_eq!(None, manifest("").hash_of(&file));
+}
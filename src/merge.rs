@@ -0,0 +1,115 @@
+use crate::file::FileContent;
+use crate::json::{creator, JsonGroup, JsonLink, JsonPath, JsonReport, FORMAT_VERSION};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::time::Duration;
+
+/// Combines reports of separate scans (e.g. of different volumes or machines) into one.
+///
+/// Groups from different reports are merged when their files have the same content, which is checked by their `JsonGroup::blake3` hashes.
+/// Reports from older versions don't have the hashes, so a file of each group is hashed instead, if it's readable here. Groups that can't be read are kept as they are.
+/// Paths that are in more than one report are listed once.
+pub fn merge_reports(reports: Vec<JsonReport>) -> io::Result<JsonReport> {
+    let mut merged = JsonReport {
+        format_version: FORMAT_VERSION,
+        creator: creator(),
+        dupes: Vec::new(),
+        groups: Vec::new(),
+        duplicate_dirs: Vec::new(),
+        hardlinks: Vec::new(),
+        roots: Vec::new(),
+        stats: Default::default(),
+        scan_duration: Duration::ZERO,
+    };
+    let mut by_content = HashMap::new();
+    for report in reports {
+        if report.format_version > FORMAT_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("report is from a newer version ({})", report.creator)));
+        }
+        // Needed to match groups by their size
+        if report.groups.len() != report.dupes.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("report doesn't have information about groups, because it's from an older version ({})", report.creator)));
+        }
+        for (sets, group) in report.dupes.into_iter().zip(report.groups) {
+            let key = group.blake3.clone().or_else(|| content_hash(&sets, &group)).map(|hash| (group.size, hash));
+            match key.as_ref().and_then(|key| by_content.get(key).copied()) {
+                Some(i) => merge_group(&mut merged.dupes[i], &mut merged.groups[i], sets, &group),
+                None => {
+                    if let Some(key) = key {
+                        by_content.insert(key, merged.dupes.len());
+                    }
+                    merged.dupes.push(sets);
+                    merged.groups.push(group);
+                },
+            }
+        }
+        merged.duplicate_dirs.extend(report.duplicate_dirs);
+        merged.hardlinks.extend(report.hardlinks);
+        merged.roots.extend(report.roots);
+        merged.stats.merge(&report.stats);
+        // Total time spent scanning
+        merged.scan_duration += report.scan_duration;
+    }
+    Ok(merged)
+}
+
+/// Hex BLAKE3 hash of the first file of the group that still has the size from the report
+fn content_hash(sets: &[Vec<JsonPath>], group: &JsonGroup) -> Option<String> {
+    sets.iter().flatten()
+        .filter_map(|path| FileContent::from_path(path.0.clone()).ok())
+        .filter(|content| content.size() == group.size)
+        .find_map(|content| content.full_hash().ok())
+        .map(|hash| hash.to_hex().to_string())
+}
+
+fn merge_group(sets: &mut Vec<Vec<JsonPath>>, group: &mut JsonGroup, other_sets: Vec<Vec<JsonPath>>, other: &JsonGroup) {
+    let known: HashSet<_> = sets.iter().flatten().cloned().collect();
+    let new_sets: Vec<Vec<_>> = other_sets.into_iter()
+        .map(|set| set.into_iter().filter(|path| !known.contains(path)).collect())
+        .filter(|set: &Vec<_>| !set.is_empty())
+        .collect();
+    if new_sets.is_empty() {
+        return;
+    }
+    sets.extend(new_sets);
+    // Files from different scans haven't been linked with each other
+    group.link = JsonLink::None;
+    group.copies += other.copies;
+    group.bytes_saved += other.bytes_saved;
+}
+
+#[test]
+fn merge_test() {
+    use crate::scanner::{RunMode, Scanner, Stats};
+    use std::fs;
+
+    let report = |dir: &tempdir::TempDir, content: &str, other: &str| {
+        for name in ["a", "b"] {
+            fs::write(dir.path().join(name), content).unwrap();
+        }
+        for name in ["c", "d"] {
+            fs::write(dir.path().join(name), other).unwrap();
+        }
+        let mut s = Scanner::new();
+        s.settings.ignore_small = false;
+        s.settings.run_mode = RunMode::DryRun;
+        s.scan(dir.path()).unwrap();
+        JsonReport::new(&s, &Stats::default(), Duration::ZERO)
+    };
+    let (dir1, dir2) = (tempdir::TempDir::new("mergetest").unwrap(), tempdir::TempDir::new("mergetest").unwrap());
+    let (report1, report2) = (report(&dir1, "same", "one"), report(&dir2, "same", "two"));
+
+    let merged = merge_reports(vec![report1, report2]).unwrap();
+    assert_eq!(3, merged.dupes.len());
+    let same = merged.groups.iter().position(|g| g.size == 4).unwrap();
+    assert_eq!(4, merged.dupes[same].iter().flatten().count());
+    assert_eq!((4, 8), (merged.groups[same].copies, merged.groups[same].bytes_saved));
+    assert_eq!(2, merged.roots.len());
+
+    // Hashes in the reports are trusted, without reading the files
+    let (mut report1, report2) = (report(&dir1, "same", "one"), report(&dir2, "same", "two"));
+    drop((dir1, dir2));
+    let other_hash = report2.groups.iter().find(|g| g.size == 3).unwrap().blake3.clone();
+    report1.groups.iter_mut().find(|g| g.size == 3).unwrap().blake3 = other_hash;
+    assert_eq!(2, merge_reports(vec![report1, report2]).unwrap().dupes.len());
+}
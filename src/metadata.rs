@@ -2,10 +2,16 @@ use std::fs;
 use std::io;
 use std::os::unix::fs::MetadataExt;
 use std::path::Path;
+use std::time::SystemTime;
 
 #[derive(Copy, Clone, Hash, Ord, PartialOrd, PartialEq, Eq, Debug, Default)]
 pub struct Metadata {
     pub dev: u64,
+    /// Bind mounts of the same filesystem have the same `dev`, but files can't be hardlinked between them.
+    /// 0 if unknown.
+    pub mount_id: u64,
+    /// Files are only compared with files of the same group, e.g. from `Scanner::add_group`. 0 for scanned files.
+    pub group: u64,
     pub size: u64,
 }
 
@@ -18,7 +24,166 @@ impl Metadata {
     pub fn new(m: &fs::Metadata) -> Self {
         Metadata {
             dev: m.dev(),
+            mount_id: 0,
+            group: 0,
             size: m.size(),
         }
     }
+
+    pub fn with_mount_id(self, mount_id: Option<u64>) -> Self {
+        Metadata {
+            mount_id: mount_id.unwrap_or(0),
+            ..self
+        }
+    }
+
+    pub fn with_group(self, group: u64) -> Self {
+        Metadata { group, ..self }
+    }
+}
+
+/// What `statx` knows about a file beyond `fs::Metadata`. Only available on Linux; elsewhere everything is unknown.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct ExtendedMetadata {
+    /// Unique for every mount, including bind mounts
+    pub mount_id: Option<u64>,
+    /// When the file has been created, if the filesystem keeps that
+    pub birth_time: Option<SystemTime>,
+    /// `STATX_ATTR_*` flags, e.g. immutable or append-only
+    pub attributes: u64,
+}
+
+impl ExtendedMetadata {
+    /// Doesn't follow symlinks
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    pub fn from_path(path: &Path) -> io::Result<Self> {
+        use std::os::unix::ffi::OsStrExt;
+        use std::time::Duration;
+
+        let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+        let mut stx: libc::statx = unsafe { std::mem::zeroed() };
+        let mask = libc::STATX_MNT_ID | libc::STATX_BTIME;
+        if unsafe { libc::statx(libc::AT_FDCWD, path.as_ptr(), libc::AT_SYMLINK_NOFOLLOW, mask, &mut stx) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Older kernels and some filesystems don't fill in everything that has been asked for
+        let birth_time = (stx.stx_mask & libc::STATX_BTIME != 0 && stx.stx_btime.tv_sec >= 0)
+            .then(|| SystemTime::UNIX_EPOCH + Duration::new(stx.stx_btime.tv_sec as u64, stx.stx_btime.tv_nsec));
+        Ok(ExtendedMetadata {
+            mount_id: (stx.stx_mask & libc::STATX_MNT_ID != 0).then_some(stx.stx_mnt_id),
+            birth_time,
+            attributes: stx.stx_attributes & stx.stx_attributes_mask,
+        })
+    }
+
+    #[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+    pub fn from_path(path: &Path) -> io::Result<Self> {
+        fs::symlink_metadata(path)?;
+        Ok(Self::default())
+    }
+
+    /// Immutable or append-only (`chattr +i`/`+a`). Such files can't be replaced or linked to, even by root.
+    pub fn is_immutable(&self) -> bool {
+        #[cfg(all(target_os = "linux", target_env = "gnu"))]
+        return self.attributes & (libc::STATX_ATTR_IMMUTABLE | libc::STATX_ATTR_APPEND) as u64 != 0;
+        #[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+        return false;
+    }
+}
+
+/// Whether the block device is a spinning disk, according to sysfs. Unknown devices (e.g. network filesystems) aren't.
+#[cfg(target_os = "linux")]
+pub(crate) fn is_rotational(dev: u64) -> bool {
+    // Same as glibc's major() and minor()
+    let major = ((dev >> 32) & 0xffff_f000) | ((dev >> 8) & 0xfff);
+    let minor = ((dev >> 12) & 0xffff_ff00) | (dev & 0xff);
+    // Partitions don't have a queue, but the disk they're on does
+    ["queue/rotational", "../queue/rotational"].iter()
+        .find_map(|file| fs::read_to_string(format!("/sys/dev/block/{}:{}/{}", major, minor, file)).ok())
+        .map_or(false, |flag| flag.trim() == "1")
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn is_rotational(_: u64) -> bool {
+    false
+}
+
+/// `user.` and `security.` extended attributes (names and values), sorted by name. Doesn't follow symlinks.
+/// Empty if the filesystem doesn't support them, or on other platforms than Linux.
+#[cfg(target_os = "linux")]
+pub(crate) fn xattrs(path: &Path) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let names = read_sized(|buf, len| unsafe { libc::llistxattr(path.as_ptr(), buf.cast(), len) })?;
+    let mut attrs = Vec::new();
+    for name in names.split(|&b| b == 0).filter(|name| name.starts_with(b"user.") || name.starts_with(b"security.")) {
+        let name_c = CString::new(name)?;
+        let value = read_sized(|buf, len| unsafe { libc::lgetxattr(path.as_ptr(), name_c.as_ptr(), buf.cast(), len) })?;
+        attrs.push((name.to_vec(), value));
+    }
+    attrs.sort();
+    Ok(attrs)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn xattrs(_: &Path) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    Ok(Vec::new())
+}
+
+/// The `security.selinux` label. `None` if the file doesn't have one, e.g. when SELinux isn't used.
+#[cfg(target_os = "linux")]
+pub(crate) fn selinux_context(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    match read_sized(|buf, len| unsafe { libc::lgetxattr(path.as_ptr(), b"security.selinux\0".as_ptr().cast(), buf.cast(), len) }) {
+        Ok(label) if !label.is_empty() => Ok(Some(label)),
+        Ok(_) => Ok(None),
+        Err(err) if err.raw_os_error() == Some(libc::ENODATA) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn selinux_context(_: &Path) -> io::Result<Option<Vec<u8>>> {
+    Ok(None)
+}
+
+/// The size is asked for first, and it's retried if it grows in the meantime
+#[cfg(target_os = "linux")]
+pub(crate) fn read_sized(call: impl Fn(*mut u8, usize) -> isize) -> io::Result<Vec<u8>> {
+    loop {
+        let len = call(std::ptr::null_mut(), 0);
+        if len < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::ENOTSUP) {
+                return Ok(Vec::new());
+            }
+            return Err(err);
+        }
+        let mut buf = vec![0; len as usize];
+        let len = call(buf.as_mut_ptr(), buf.len());
+        if len >= 0 {
+            buf.truncate(len as usize);
+            return Ok(buf);
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+    }
+}
+
+#[test]
+fn extended_metadata_test() {
+    let dir = tempdir::TempDir::new("metatest").unwrap();
+    let path = dir.path().join("a");
+    fs::write(&path, "a").unwrap();
+    let m = ExtendedMetadata::from_path(&path).unwrap();
+    // Files in the same directory are on the same mount
+    assert_eq!(m.mount_id, ExtendedMetadata::from_path(dir.path()).unwrap().mount_id);
+    assert!(ExtendedMetadata::from_path(&dir.path().join("missing")).is_err());
 }
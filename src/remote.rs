@@ -0,0 +1,36 @@
+use crate::manifest::HashManifest;
+use crate::script::shell_quote;
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Hashes of all files in a directory on another machine, for `Scanner::import_hashes`, to find local files that already have a copy there.
+///
+/// `target` is `[user@]host:dir`, like in `scp`. It runs `dupe-krill` over `ssh`, so it has to be installed on that machine too.
+/// Nothing is changed there.
+pub fn remote_hashes(target: &str) -> io::Result<HashManifest> {
+    let (host, dir) = target.split_once(':')
+        .filter(|(host, dir)| !host.is_empty() && !dir.is_empty())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("expected [user@]host:dir, got '{}'", target)))?;
+    let mut command = b"dupe-krill --dry-run --quiet --small --empty --export-hashes - -- ".to_vec();
+    command.extend(shell_quote(Path::new(dir)));
+    let mut child = Command::new("ssh")
+        .arg("--")
+        .arg(host)
+        .arg(OsStr::from_bytes(&command))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        // Warnings about files that couldn't be read there are shown here
+        .stderr(Stdio::inherit())
+        .spawn()
+        .map_err(|err| io::Error::new(err.kind(), format!("can't run ssh: {}", err)))?;
+    let manifest = HashManifest::from_remote(child.stdout.take().unwrap(), host);
+    let status = child.wait()?;
+    // 2 means some files couldn't be read, but the rest have been hashed
+    if !matches!(status.code(), Some(0 | 2)) {
+        return Err(io::Error::new(io::ErrorKind::Other, format!("dupe-krill on {} failed ({})", host, status)));
+    }
+    manifest
+}
@@ -0,0 +1,162 @@
+use crate::json::{content_hash, JsonPath};
+use crate::scanner::ScanListener;
+use crate::scanner::Scanner;
+use crate::scanner::Stats;
+use serde_derive::Serialize;
+use std::env;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Prints duplicates in the same JSON format as `rmlint --output json`, for tools that post-process rmlint's results.
+///
+/// The first file of each group is marked as the original. Paths are absolute, like in rmlint.
+#[derive(Debug, Default)]
+pub struct RmlintOutput;
+
+#[derive(Serialize)]
+struct Header {
+    description: &'static str,
+    cwd: JsonPath,
+    args: String,
+    version: &'static str,
+    rev: &'static str,
+    progress: u32,
+    checksum_type: &'static str,
+}
+
+#[derive(Serialize)]
+struct Entry {
+    id: usize,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    progress: u32,
+    checksum: String,
+    path: JsonPath,
+    size: u64,
+    depth: usize,
+    inode: u64,
+    disk_id: u64,
+    is_original: bool,
+    mtime: f64,
+}
+
+#[derive(Serialize)]
+struct Footer {
+    aborted: bool,
+    progress: u32,
+    total_files: usize,
+    ignored_files: usize,
+    ignored_folders: usize,
+    duplicates: usize,
+    duplicate_sets: usize,
+    total_lint_size: u64,
+}
+
+impl RmlintOutput {
+    pub fn new() -> Self {
+        RmlintOutput
+    }
+
+    fn write(&self, scanner: &Scanner, stats: &Stats, out: &mut impl Write) -> io::Result<()> {
+        let cwd = env::current_dir()?;
+        writeln!(out, "[")?;
+        serde_json::to_writer_pretty(&mut *out, &Header {
+            description: "rmlint json-dump of lint files",
+            cwd: JsonPath(cwd.clone().into_boxed_path()),
+            args: env::args().collect::<Vec<_>>().join(" "),
+            version: env!("CARGO_PKG_VERSION"),
+            rev: "dupe-krill",
+            progress: 0,
+            checksum_type: "blake3",
+        })?;
+
+        let mut footer = Footer {
+            aborted: scanner.stopped(),
+            progress: 100,
+            total_files: stats.added,
+            ignored_files: stats.skipped,
+            ignored_folders: 0,
+            duplicates: 0,
+            duplicate_sets: 0,
+            total_lint_size: 0,
+        };
+        let mut id = 0;
+        for (content, sets) in scanner.dupes_with_content_iter() {
+            let paths: Vec<_> = sets.into_iter().flat_map(|set| set.paths).collect();
+            if paths.len() < 2 {
+                continue;
+            }
+            let checksum = match content_hash(content, paths.iter().map(|path| &**path)) {
+                Some(hash) => hash.to_hex().to_string(),
+                None => continue,
+            };
+            footer.duplicate_sets += 1;
+            for (i, path) in paths.iter().enumerate() {
+                let m = match fs::symlink_metadata(path) {
+                    Ok(m) => m,
+                    Err(err) => {
+                        eprintln!("{}: {}", path.display(), err);
+                        continue;
+                    },
+                };
+                let is_original = i == 0;
+                if !is_original {
+                    footer.duplicates += 1;
+                    footer.total_lint_size += m.len();
+                }
+                id += 1;
+                let path = absolute(&cwd, path);
+                writeln!(out, ",")?;
+                serde_json::to_writer_pretty(&mut *out, &Entry {
+                    id,
+                    kind: "duplicate_file",
+                    progress: 100,
+                    checksum: checksum.clone(),
+                    depth: path.components().count().saturating_sub(1),
+                    path: JsonPath(path.into_boxed_path()),
+                    size: m.len(),
+                    inode: m.ino(),
+                    disk_id: m.dev(),
+                    is_original,
+                    mtime: m.mtime() as f64 + m.mtime_nsec() as f64 / 1e9,
+                })?;
+            }
+        }
+        writeln!(out, ",")?;
+        serde_json::to_writer_pretty(&mut *out, &footer)?;
+        writeln!(out, "\n]")?;
+        out.flush()
+    }
+}
+
+fn absolute(cwd: &Path, path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        cwd.join(path)
+    }
+}
+
+impl ScanListener for RmlintOutput {
+    fn file_scanned(&mut self, _: &Path, _: &Stats) {
+        // output only at scan_over
+    }
+
+    fn scan_over(&self, scanner: &Scanner, stats: &Stats, _: Duration) {
+        if let Err(err) = self.write(scanner, stats, &mut io::BufWriter::new(io::stdout().lock())) {
+            eprintln!("Can't write the report: {}", err);
+        }
+    }
+
+    fn hardlinked(&mut self, _: &Path, _: &Path) {
+        // output only at scan_over
+    }
+
+    fn duplicate_found(&mut self, _: &Path, _: &Path) {
+        // output only at scan_over
+    }
+}
@@ -1,5 +1,15 @@
-use crate::file::{FileContent, FileSet};
-use crate::metadata::Metadata;
+use crate::acl;
+use crate::checkpoint::Checkpoint;
+use crate::exclude::{self, ExcludeRules};
+use crate::file::{files_identical, FileContent, FileSet};
+use crate::hasher::{ChunkSizes, Hasher};
+use crate::lazyfile::{self, ReadOptions};
+use crate::manifest::{self, HashManifest};
+use crate::metadata::{self, ExtendedMetadata, Metadata};
+use crate::temp::{sync_parent_dir, temp_file_status, temp_path};
+use crate::snapshot;
+use crate::trash;
+use crate::undo::{self, UndoLog};
 use std::cell::RefCell;
 use std::cmp;
 use std::collections::btree_map::Entry as BTreeEntry;
@@ -8,16 +18,18 @@ use std::collections::BTreeMap;
 use std::collections::BinaryHeap;
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
 use std::fmt::Debug;
 use std::fs;
 use std::io;
-use std::os::unix::fs::MetadataExt;
-use std::path::Path;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirEntryExt, MetadataExt};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use std::sync::atomic::AtomicU32;
+use std::sync::atomic::{AtomicBool, AtomicU32};
 use std::sync::atomic::Ordering;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum RunMode {
@@ -26,6 +38,40 @@ pub enum RunMode {
     /// Like dry run, but completely skips deduping, with no UI for dupes.
     DryRunNoMerging,
     Hardlink,
+    /// Moves duplicates to the trash (or `Settings::trash_dir`) instead of hardlinking them, keeping one copy
+    Trash,
+}
+
+/// Which file of a group of duplicates is kept, and becomes the source of links to the others
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Keep {
+    /// The file that already has the most hardlinks, which needs the fewest changes
+    #[default]
+    MostLinks,
+    /// The file with the oldest modification time, so that the original timestamps are preserved
+    Oldest,
+}
+
+/// Modification time given to the kept file after other copies have been linked to it
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum LinkedMtime {
+    /// Whatever the kept file had
+    #[default]
+    Unchanged,
+    /// The oldest of the linked copies
+    Oldest,
+    /// The newest of the linked copies
+    Newest,
+}
+
+impl LinkedMtime {
+    fn pick(self, current: SystemTime, linked: SystemTime) -> SystemTime {
+        match self {
+            LinkedMtime::Unchanged => current,
+            LinkedMtime::Oldest => current.min(linked),
+            LinkedMtime::Newest => current.max(linked),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -33,13 +79,129 @@ pub struct Settings {
     /// Ignore files smaller than a filesystem block.
     /// Deduping of such files is unlikely to save space.
     pub ignore_small: bool,
+    /// Empty files are skipped unless this is set. They're all identical, so it's only useful for reporting or reducing the number of inodes.
+    pub include_empty: bool,
     pub run_mode: RunMode,
+    /// Count all files before scanning, so that the listener can show how much is left
+    pub count_first: bool,
+    /// Files are only linked with files from other scan roots, never with files in the same root.
+    /// Useful for deduping snapshots against each other, without changing what's inside each snapshot.
+    pub isolate_roots: bool,
+    /// Listeners should report `Scanner::duplicate_dirs()` at the end
+    pub find_duplicate_dirs: bool,
+    /// Listeners should report `Scanner::hardlink_groups()` at the end
+    pub report_hardlinks: bool,
+    /// Listeners should report `Scanner::access_conflicts()` at the end
+    pub report_access_conflicts: bool,
+    /// Reference, protected, and preferred files are kept regardless of this
+    pub keep: Keep,
+    /// E.g. for backup tools that look at modification times, which could otherwise change depending on which copy has been kept
+    pub linked_mtime: LinkedMtime,
+    /// Files aren't linked if their `user.` or `security.` extended attributes differ, since only the kept file's attributes would remain (Linux only)
+    pub require_same_xattrs: bool,
+    /// Files with different SELinux contexts are linked anyway. A hardlinked file has only one context, so services confined to the other one may lose access to it.
+    pub ignore_selinux: bool,
+    /// When a file owned by another user is replaced, that user is given read access to the kept file with an ACL entry (Linux only)
+    pub grant_read_access: bool,
+    /// Only files modified at least this long ago (before the scanner has been created) are deduped
+    pub older_than: Option<Duration>,
+    /// Only files modified within this time are deduped
+    pub newer_than: Option<Duration>,
+    /// Only files owned by this user id are deduped
+    pub owner: Option<u32>,
+    /// Only files belonging to this group id are deduped
+    pub group: Option<u32>,
+    /// Files that already have this many hardlinks are skipped (e.g. because they're managed by another tool)
+    pub max_links: Option<u64>,
+    /// Files that other processes have open for writing are skipped, instead of replacing a file that is still being written.
+    /// Only works on Linux, for files the user owns.
+    pub skip_open_files: bool,
+    /// Files are compared byte by byte right before one is replaced. Set by `Scanner::import_hashes`, since imported hashes may be out of date.
+    pub verify_before_link: bool,
+    /// With `RunMode::Trash`, duplicates are moved to this directory (under their path relative to the scanned directory),
+    /// instead of the freedesktop.org trash. It has to be on the same filesystem.
+    pub trash_dir: Option<PathBuf>,
+    /// Also scan snapshot directories (`.snapshot`, `.zfs`, read-only Btrfs subvolumes, Time Machine backups),
+    /// which are skipped by default, because their files are read-only.
+    pub scan_snapshots: bool,
+    /// Skip version control and cache directories, and macOS metadata files (see `ExcludeRules::defaults()`). Rules added with `Scanner::exclude_rules` can re-include them.
+    pub default_excludes: bool,
+    /// Called for every file and directory found. Ones for which it returns `false` are skipped, including everything in such directories.
+    pub filter: Option<FileFilter>,
+    /// How files are opened for comparing, e.g. to avoid changing their access times
+    pub read_options: ReadOptions,
+    /// How much of files is read at a time when comparing. Smaller chunks use less memory, larger ones are faster on fast disks.
+    pub chunk_sizes: ChunkSizes,
+    /// Files on hard disks are read in larger chunks (see `ChunkSizes::for_hard_disk`) and with read-ahead. Detected on Linux only.
+    pub tune_for_hard_disks: bool,
+    /// Hash whole files while comparing them, so that `Scanner::export_hashes` doesn't need to read them again.
+    /// Costs some memory per file.
+    pub full_hashes: bool,
+
+    /// How many times to retry linking and renaming that failed with a temporary error (e.g. `ESTALE` on network filesystems)
+    pub link_retries: u32,
+    /// Stop scanning after this much time since the scanner has been created
+    pub max_runtime: Option<Duration>,
+    /// Stop scanning once duplicates of this many bytes have been found
+    pub stop_after_saving: Option<u64>,
+    /// Stop scanning once this many groups of duplicates have been found
+    pub stop_after_groups: Option<usize>,
 
     // If 1, go to flush. If > 1, abort immediately.
     pub break_on: Option<&'static AtomicU32>,
+    /// When set to true (e.g. from a signal handler), current status is printed to stderr, and the flag is cleared
+    pub status_on: Option<&'static AtomicBool>,
+}
+
+type FilterFn = dyn Fn(&Path, &fs::Metadata) -> bool;
+
+/// Custom logic for `Settings::filter`
+pub struct FileFilter(Box<FilterFn>);
+
+impl FileFilter {
+    pub fn new(filter: impl Fn(&Path, &fs::Metadata) -> bool + 'static) -> Self {
+        FileFilter(Box::new(filter))
+    }
+}
+
+impl Debug for FileFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("FileFilter")
+    }
 }
 
 impl Settings {
+    fn is_excluded_by_age(&self, metadata: &fs::Metadata, now: SystemTime) -> bool {
+        if self.older_than.is_none() && self.newer_than.is_none() {
+            return false;
+        }
+        // Files from the future are treated as brand new
+        let age = metadata.modified().ok()
+            .and_then(|mtime| now.duration_since(mtime).ok())
+            .unwrap_or_default();
+        self.older_than.map_or(false, |min| age < min) || self.newer_than.map_or(false, |max| age >= max)
+    }
+
+    /// `older_than` checked again right before a file is replaced or linked to, since it may have been modified after it has been scanned
+    fn is_modified_too_recently(&self, path: &Path) -> bool {
+        let min = match self.older_than {
+            Some(min) => min,
+            None => return false,
+        };
+        fs::symlink_metadata(path).ok()
+            .and_then(|m| m.modified().ok())
+            .and_then(|mtime| SystemTime::now().duration_since(mtime).ok())
+            .map_or(true, |age| age < min)
+    }
+
+    fn is_excluded_by_owner(&self, metadata: &fs::Metadata) -> bool {
+        self.owner.map_or(false, |uid| metadata.uid() != uid) || self.group.map_or(false, |gid| metadata.gid() != gid)
+    }
+
+    fn status_requested(&self) -> bool {
+        self.status_on.map_or(false, |flag| flag.swap(false, Ordering::SeqCst))
+    }
+
     pub fn breaks(&self) -> u32 {
         if let Some(break_on) = self.break_on {
             break_on.load(Ordering::SeqCst)
@@ -49,15 +211,169 @@ impl Settings {
     }
 }
 
-#[derive(Debug, Default, Copy, Clone)]
-#[cfg_attr(feature = "json", derive(serde_derive::Serialize))]
+#[derive(Debug, Default, Clone)]
+#[cfg_attr(feature = "json", derive(serde_derive::Serialize, serde_derive::Deserialize))]
 pub struct Stats {
     pub added: usize,
+    /// Total size of added files, counting every hardlink separately
+    #[cfg_attr(feature = "json", serde(default))]
+    pub bytes_added: usize,
+    /// Total size of files that had to be compared, because they have the same size as another file
+    #[cfg_attr(feature = "json", serde(default))]
+    pub bytes_compared: usize,
+    /// How much of `bytes_compared` has actually been read. Comparisons stop at the first difference.
+    #[cfg_attr(feature = "json", serde(default))]
+    pub bytes_read: usize,
     pub skipped: usize,
     pub dupes: usize,
     pub bytes_deduplicated: usize,
     pub hardlinks: usize,
     pub bytes_saved_by_hardlinks: usize,
+    /// Disk space allocated to the dupes (from `st_blocks`).
+    /// It's smaller than `bytes_deduplicated` for sparse and compressed files, and larger for files that don't fill their last block.
+    #[cfg_attr(feature = "json", serde(default))]
+    pub allocated_bytes_deduplicated: usize,
+    /// Like `bytes_saved_by_hardlinks`, but from `st_blocks`
+    #[cfg_attr(feature = "json", serde(default))]
+    pub allocated_bytes_saved_by_hardlinks: usize,
+    /// Why files have been skipped. Adds up to `skipped`.
+    #[cfg_attr(feature = "json", serde(default))]
+    pub skipped_by_reason: SkippedStats,
+    /// Breakdown by scan root, in the same order as `Scanner::roots()`
+    #[cfg_attr(feature = "json", serde(default))]
+    pub by_root: Vec<PartialStats>,
+    /// Breakdown by filesystem (`st_dev`)
+    #[cfg_attr(feature = "json", serde(default))]
+    pub by_device: BTreeMap<u64, PartialStats>,
+    /// Bytes of dupes by lowercase file extension (without the dot). Files without an extension are under `""`.
+    #[cfg_attr(feature = "json", serde(default))]
+    pub bytes_by_extension: BTreeMap<String, usize>,
+}
+
+/// Part of `Stats` for one scan root or filesystem
+#[derive(Debug, Default, Copy, Clone)]
+#[cfg_attr(feature = "json", derive(serde_derive::Serialize, serde_derive::Deserialize))]
+pub struct PartialStats {
+    pub added: usize,
+    pub dupes: usize,
+    pub bytes_deduplicated: usize,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+#[cfg_attr(feature = "json", derive(serde_derive::Serialize, serde_derive::Deserialize))]
+pub struct SkippedStats {
+    /// Empty, or smaller than a block if `Settings::ignore_small` is set
+    pub too_small: usize,
+    pub symlink: usize,
+    /// Devices, sockets, pipes
+    pub special: usize,
+    /// By exclude patterns or other filters
+    pub excluded: usize,
+    /// Directories that couldn't be read
+    pub error: usize,
+    /// Opened for writing by another process, with `Settings::skip_open_files`
+    #[cfg_attr(feature = "json", serde(default))]
+    pub open_for_writing: usize,
+}
+
+/// Category of `SkippedStats`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    TooSmall,
+    Symlink,
+    Special,
+    Excluded,
+    Error,
+    OpenForWriting,
+}
+
+impl SkipReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SkipReason::TooSmall => "too small",
+            SkipReason::Symlink => "symlink",
+            SkipReason::Special => "special",
+            SkipReason::Excluded => "excluded",
+            SkipReason::Error => "error",
+            SkipReason::OpenForWriting => "open for writing",
+        }
+    }
+}
+
+impl Stats {
+    /// Number of added files with distinct contents
+    pub fn unique_files(&self) -> usize {
+        self.added.saturating_sub(self.dupes + self.hardlinks)
+    }
+
+    /// Size of the added files if every content was stored only once
+    pub fn unique_bytes(&self) -> usize {
+        self.bytes_added.saturating_sub(self.bytes_deduplicated + self.bytes_saved_by_hardlinks)
+    }
+
+    /// How many times each content is stored on average, counting hardlinks as copies. 1.0 if there are no duplicates.
+    pub fn dedupe_ratio(&self) -> f64 {
+        let unique = self.unique_bytes();
+        if unique == 0 {
+            return 1.;
+        }
+        self.bytes_added as f64 / unique as f64
+    }
+
+    /// Adds totals of another scan, e.g. of another volume. `by_root` of `other` is appended, since its roots are too.
+    pub fn merge(&mut self, other: &Stats) {
+        self.added += other.added;
+        self.bytes_added += other.bytes_added;
+        self.bytes_compared += other.bytes_compared;
+        self.bytes_read += other.bytes_read;
+        self.skipped += other.skipped;
+        self.dupes += other.dupes;
+        self.bytes_deduplicated += other.bytes_deduplicated;
+        self.hardlinks += other.hardlinks;
+        self.bytes_saved_by_hardlinks += other.bytes_saved_by_hardlinks;
+        self.allocated_bytes_deduplicated += other.allocated_bytes_deduplicated;
+        self.allocated_bytes_saved_by_hardlinks += other.allocated_bytes_saved_by_hardlinks;
+        let (s, o) = (&mut self.skipped_by_reason, &other.skipped_by_reason);
+        s.too_small += o.too_small;
+        s.symlink += o.symlink;
+        s.special += o.special;
+        s.excluded += o.excluded;
+        s.error += o.error;
+        s.open_for_writing += o.open_for_writing;
+        self.by_root.extend_from_slice(&other.by_root);
+        for (&dev, o) in &other.by_device {
+            let s = self.by_device.entry(dev).or_default();
+            s.added += o.added;
+            s.dupes += o.dupes;
+            s.bytes_deduplicated += o.bytes_deduplicated;
+        }
+        for (ext, &bytes) in &other.bytes_by_extension {
+            *self.bytes_by_extension.entry(ext.clone()).or_insert(0) += bytes;
+        }
+    }
+
+    fn skip(&mut self, reason: SkipReason) {
+        self.skipped += 1;
+        let s = &mut self.skipped_by_reason;
+        *match reason {
+            SkipReason::TooSmall => &mut s.too_small,
+            SkipReason::Symlink => &mut s.symlink,
+            SkipReason::Special => &mut s.special,
+            SkipReason::Excluded => &mut s.excluded,
+            SkipReason::Error => &mut s.error,
+            SkipReason::OpenForWriting => &mut s.open_for_writing,
+        } += 1;
+    }
+
+    fn count(&mut self, root: Option<usize>, dev: u64, update: impl Fn(&mut PartialStats)) {
+        if let Some(root) = root {
+            if self.by_root.len() <= root {
+                self.by_root.resize(root + 1, PartialStats::default());
+            }
+            update(&mut self.by_root[root]);
+        }
+        update(self.by_device.entry(dev).or_default());
+    }
 }
 
 pub trait ScanListener: Debug {
@@ -65,6 +381,57 @@ pub trait ScanListener: Debug {
     fn scan_over(&self, scanner: &Scanner, stats: &Stats, scan_duration: Duration);
     fn hardlinked(&mut self, src: &Path, dst: &Path);
     fn duplicate_found(&mut self, src: &Path, dst: &Path);
+
+    /// A file or directory hasn't been added to the scan
+    fn skipped(&mut self, _path: &Path, _reason: SkipReason) {}
+
+    /// Called before the error is printed to stderr
+    fn error(&mut self, _path: &Path, _err: &io::Error) {}
+
+    /// In `RunMode::Trash`, a duplicate of `kept` has been moved from `path` to `trash_path`
+    fn trashed(&mut self, _path: &Path, _kept: &Path, _trash_path: &Path) {}
+
+    /// Called before scanning if `Settings::count_first` is enabled.
+    /// `files` is the expected final sum of `Stats::added` and `Stats::skipped`.
+    fn totals_counted(&mut self, _files: usize, _bytes: u64) {}
+
+    /// A scanned file is identical to a file that is only listed in an imported `HashManifest` (e.g. from another machine).
+    /// Called at the end of the scan, once per group of identical files.
+    fn duplicate_in_manifest(&mut self, _path: &Path, _manifest_path: &Path) {}
+
+    /// `dst` is about to be replaced with a hardlink of `src` (or moved to the trash in `RunMode::Trash`), e.g. to ask the user first.
+    /// With `Decision::Skip` it's left as it is. Not called in dry runs.
+    fn before_link(&mut self, _src: &Path, _dst: &Path) -> Decision {
+        Decision::Proceed
+    }
+
+    /// The scan has moved to another phase. Phases can repeat, e.g. deferred files are linked periodically during the scan.
+    fn phase_started(&mut self, _phase: Phase) {}
+
+    /// How far the current phase is. In `Phase::Scanning` it's the number of directories scanned, and the total grows as subdirectories are found.
+    /// In `Phase::LinkingDeferred` it's the number of groups of files of the same size. Not called in other phases.
+    fn phase_progress(&mut self, _phase: Phase, _done: usize, _total: usize) {}
+}
+
+/// Part of the scan, for `ScanListener::phase_started`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Phase {
+    /// Counting files before scanning, if `Settings::count_first` is enabled
+    Counting,
+    /// Reading directories, and comparing files as they're found. Most duplicates are linked right away.
+    Scanning,
+    /// Linking duplicates that had other hardlinks, which has been put off until more of their hardlinks are known
+    LinkingDeferred,
+    /// Hashing scanned files to find files that are only in an imported `HashManifest`
+    MatchingManifest,
+}
+
+/// Returned by `ScanListener::before_link`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Decision {
+    Proceed,
+    /// Leave the duplicate unchanged
+    Skip,
 }
 
 #[derive(Debug)]
@@ -79,12 +446,110 @@ impl ScanListener for SilentListener {
     fn duplicate_found(&mut self, _: &Path, _: &Path) {}
 }
 
+/// Forwards events to all of its listeners, in order
+#[derive(Debug, Default)]
+pub struct MultiListener {
+    listeners: Vec<Box<dyn ScanListener>>,
+}
+
+impl MultiListener {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, listener: Box<dyn ScanListener>) {
+        self.listeners.push(listener);
+    }
+}
+
+impl ScanListener for MultiListener {
+    fn file_scanned(&mut self, path: &Path, stats: &Stats) {
+        for l in &mut self.listeners {
+            l.file_scanned(path, stats);
+        }
+    }
+
+    fn scan_over(&self, scanner: &Scanner, stats: &Stats, scan_duration: Duration) {
+        for l in &self.listeners {
+            l.scan_over(scanner, stats, scan_duration);
+        }
+    }
+
+    fn hardlinked(&mut self, src: &Path, dst: &Path) {
+        for l in &mut self.listeners {
+            l.hardlinked(src, dst);
+        }
+    }
+
+    fn duplicate_found(&mut self, src: &Path, dst: &Path) {
+        for l in &mut self.listeners {
+            l.duplicate_found(src, dst);
+        }
+    }
+
+    fn totals_counted(&mut self, files: usize, bytes: u64) {
+        for l in &mut self.listeners {
+            l.totals_counted(files, bytes);
+        }
+    }
+
+    fn skipped(&mut self, path: &Path, reason: SkipReason) {
+        for l in &mut self.listeners {
+            l.skipped(path, reason);
+        }
+    }
+
+    /// Any listener can veto it
+    fn before_link(&mut self, src: &Path, dst: &Path) -> Decision {
+        for l in &mut self.listeners {
+            if l.before_link(src, dst) == Decision::Skip {
+                return Decision::Skip;
+            }
+        }
+        Decision::Proceed
+    }
+
+    fn phase_started(&mut self, phase: Phase) {
+        for l in &mut self.listeners {
+            l.phase_started(phase);
+        }
+    }
+
+    fn phase_progress(&mut self, phase: Phase, done: usize, total: usize) {
+        for l in &mut self.listeners {
+            l.phase_progress(phase, done, total);
+        }
+    }
+
+    fn error(&mut self, path: &Path, err: &io::Error) {
+        for l in &mut self.listeners {
+            l.error(path, err);
+        }
+    }
+
+    fn trashed(&mut self, path: &Path, kept: &Path, trash_path: &Path) {
+        for l in &mut self.listeners {
+            l.trashed(path, kept, trash_path);
+        }
+    }
+
+    fn duplicate_in_manifest(&mut self, path: &Path, manifest_path: &Path) {
+        for l in &mut self.listeners {
+            l.duplicate_in_manifest(path, manifest_path);
+        }
+    }
+}
+
 type RcFileSet = Rc<RefCell<FileSet>>;
 
 #[derive(Debug)]
 pub struct Scanner {
     /// All hardlinks of the same inode have to be treated as the same file
     by_inode: HashMap<(u64, u64), RcFileSet>,
+    /// Files are first grouped by device and size. A file of unique size can't have dupes,
+    /// so it's kept here (as `Some`) without the cost of an entry in `by_content`.
+    /// Once there's a second file of the same size, both go to `by_content` and this becomes `None`.
+    by_size: HashMap<Metadata, Option<RcFileSet>>,
     /// See Hasher for explanation
     by_content: BTreeMap<FileContent, Vec<RcFileSet>>,
     /// Directories left to scan. Sorted by inode number.
@@ -92,13 +557,42 @@ pub struct Scanner {
     /// which is related to its physical position on disk, which makes the scan more sequential.
     to_scan: BinaryHeap<(u64, Box<Path>)>,
 
+    /// Paths given to `enqueue`. Scanned files have paths starting with them.
+    roots: Vec<Box<Path>>,
+    /// To avoid scanning the same root twice under different names
+    canonical_roots: HashSet<Box<Path>>,
+    /// Allocation unit of every filesystem seen, by device
+    block_sizes: HashMap<u64, u64>,
+    /// Devices that are hard disks, for `Settings::tune_for_hard_disks`
+    rotational: HashMap<u64, bool>,
+    /// Cache for `mount_id()`
+    last_mount_id: Option<(Box<Path>, Option<u64>)>,
+    /// Of files being added by `add_group()`, 0 otherwise
+    group: u64,
+    /// Number of `add_group()` calls so far
+    groups_added: u64,
+
     scan_listener: Box<dyn ScanListener>,
+    undo_log: Option<UndoLog>,
+    /// Working directory at the start of the scan, for `absolute()`
+    cwd: PathBuf,
     stats: Stats,
     exclude: HashSet<OsString>,
+    exclude_rules: ExcludeRules,
+    default_exclude_rules: ExcludeRules,
+    protected: Protected,
+    /// Hashes from `import_hashes`
+    manifest: HashManifest,
     pub settings: Settings,
 
     deferred_count: usize,
     next_deferred_count: usize,
+    /// Reference time for age filters
+    created: SystemTime,
+    /// For `max_runtime`
+    started: Instant,
+    /// For `stop_after_groups`
+    dupe_groups: usize,
 }
 
 impl Scanner {
@@ -106,22 +600,82 @@ impl Scanner {
         Scanner {
             settings: Settings {
                 ignore_small: true,
+                include_empty: false,
                 run_mode: RunMode::Hardlink,
+                count_first: false,
+                isolate_roots: false,
+                find_duplicate_dirs: false,
+                report_hardlinks: false,
+                report_access_conflicts: false,
+                keep: Keep::MostLinks,
+                linked_mtime: LinkedMtime::Unchanged,
+                require_same_xattrs: false,
+                ignore_selinux: false,
+                grant_read_access: false,
+                older_than: None,
+                newer_than: None,
+                owner: None,
+                group: None,
+                max_links: None,
+                skip_open_files: false,
+                verify_before_link: false,
+                trash_dir: None,
+                scan_snapshots: false,
+                default_excludes: true,
+                filter: None,
+                read_options: ReadOptions::default(),
+                chunk_sizes: ChunkSizes::default(),
+                tune_for_hard_disks: true,
+                full_hashes: false,
+                link_retries: 3,
+                max_runtime: None,
+                stop_after_saving: None,
+                stop_after_groups: None,
                 break_on: None,
+                status_on: None,
             },
             by_inode: HashMap::new(),
+            by_size: HashMap::new(),
             by_content: BTreeMap::new(),
             to_scan: BinaryHeap::new(),
+            roots: Vec::new(),
+            canonical_roots: HashSet::new(),
+            block_sizes: HashMap::new(),
+            rotational: HashMap::new(),
+            last_mount_id: None,
+            group: 0,
+            groups_added: 0,
             scan_listener: Box::new(SilentListener),
+            undo_log: None,
+            cwd: std::env::current_dir().unwrap_or_default(),
             stats: Stats::default(),
             exclude: HashSet::new(),
+            exclude_rules: ExcludeRules::new(),
+            default_exclude_rules: ExcludeRules::defaults(),
+            protected: Protected::default(),
+            manifest: HashManifest::new(),
             deferred_count: 0,
             next_deferred_count: 4096,
+            created: SystemTime::now(),
+            started: Instant::now(),
+            dupe_groups: 0,
         }
     }
 
     pub fn exclude(&mut self, exclude: Vec<String>) {
-        self.exclude = exclude.into_iter().map(From::from).collect();
+        self.exclude = exclude.into_iter().map(|name| OsStr::from_bytes(&exclude::normalized(name.as_bytes())).to_owned()).collect();
+    }
+
+    /// Use hashes from the manifest instead of reading files that haven't changed since, and report files identical to ones only listed in it.
+    /// Files are then compared by hashes of their whole content, and byte by byte before linking. Must be called before scanning.
+    pub fn import_hashes(&mut self, manifest: HashManifest) {
+        self.settings.verify_before_link |= manifest.has_local();
+        self.manifest.extend(manifest);
+    }
+
+    /// Add rsync-style exclude patterns, matched against paths relative to the scan root
+    pub fn exclude_rules(&mut self, rules: ExcludeRules) {
+        self.exclude_rules.extend(rules);
     }
 
     /// Set the scan listener. Caution: This overrides previously set listeners!
@@ -130,6 +684,22 @@ impl Scanner {
         self.scan_listener = listener;
     }
 
+    /// Files matching these rules (relative to the scan root) can be used as link sources, but are never replaced themselves
+    pub fn protect_rules(&mut self, rules: ExcludeRules) {
+        self.protected.rules.extend(rules);
+    }
+
+    /// When files matching these rules (relative to the scan root) have duplicates, they're the ones that are kept, and the others are linked to them.
+    /// Unlike `protect_rules`, the files can still be replaced by reference or protected files.
+    pub fn keep_matching(&mut self, rules: ExcludeRules) {
+        self.protected.preferred_rules.extend(rules);
+    }
+
+    /// Record every replaced file in an undo log, so that it can be restored with `dupe_krill::undo()`
+    pub fn set_undo_log(&mut self, undo_log: UndoLog) {
+        self.undo_log = Some(undo_log);
+    }
+
     /// Scan any file or directory for dupes.
     /// Dedupe is done within the path as well as against all previously added paths.
     pub fn scan(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
@@ -139,26 +709,206 @@ impl Scanner {
     }
 
     pub fn enqueue(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
-        let path = fs::canonicalize(path)?.into_boxed_path();
-        let metadata = fs::symlink_metadata(&path)?;
-        self.add(path, &metadata)?;
+        self.enqueue_root(path.as_ref(), Protection::None)
+    }
+
+    /// Like `enqueue`, but files in this path are never modified. They're only used as link sources for other files.
+    pub fn enqueue_reference(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.enqueue_root(path.as_ref(), Protection::Reference)
+    }
+
+    /// Like `enqueue`, but when files in this path have duplicates elsewhere, they're always the ones that are kept
+    /// (with their inode and metadata), and the others are linked to them. Reference and protected files still take precedence.
+    pub fn enqueue_preferred(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.enqueue_root(path.as_ref(), Protection::Preferred)
+    }
+
+    /// Makes the path a root, like `enqueue`, but doesn't scan it. Rules relative to roots apply to files from `add_files` and `add_group` in it,
+    /// and only files in roots are replaced.
+    pub fn add_root(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.register_root(path.as_ref(), Protection::None).map(drop)
+    }
+
+    /// Like `enqueue_reference`, but doesn't scan the path
+    pub fn add_reference_root(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.register_root(path.as_ref(), Protection::Reference).map(drop)
+    }
+
+    /// Like `enqueue_preferred`, but doesn't scan the path
+    pub fn add_preferred_root(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.register_root(path.as_ref(), Protection::Preferred).map(drop)
+    }
+
+    fn enqueue_root(&mut self, path: &Path, protection: Protection) -> io::Result<()> {
+        if let Some((path, metadata)) = self.register_root(path, protection)? {
+            self.add(path, &metadata)?;
+        }
         Ok(())
     }
 
+    /// The path and its metadata, unless it's a root already
+    fn register_root(&mut self, path: &Path, protection: Protection) -> io::Result<Option<(Box<Path>, fs::Metadata)>> {
+        let canonical = fs::canonicalize(path)?.into_boxed_path();
+        // The root may be a symlink (e.g. to another volume), and then its target is scanned
+        let metadata = fs::metadata(&canonical)?;
+        // Paths are kept as given by the user, so that the output uses the same paths.
+        // Symlinked files are the exception, since they'd be replaced themselves instead of their target.
+        let path: Box<Path> = if metadata.is_dir() || !fs::symlink_metadata(path)?.file_type().is_symlink() {
+            path.into()
+        } else {
+            canonical.clone()
+        };
+        // Marked even if it has been scanned already, e.g. when resuming from a checkpoint
+        match protection {
+            Protection::Reference => self.protected.reference_roots.push(path.clone()),
+            Protection::Preferred => self.protected.preferred_roots.push(path.clone()),
+            Protection::None | Protection::Rules => {},
+        }
+        if !self.canonical_roots.insert(canonical) {
+            return Ok(None);
+        }
+        self.roots.push(path.clone());
+        Ok(Some((path, metadata)))
+    }
+
+    /// Adds exactly these files, for callers that find files themselves. Paths are used as-is, without walking directories or resolving symlinks.
+    /// Directories, symlinks, and special files are skipped. Files are deduped as they're added, and the rest in `flush()`.
+    /// If it's combined with `scan()`, only files inside the scanned roots are replaced.
+    pub fn add_files(&mut self, paths: impl IntoIterator<Item = PathBuf>) {
+        for path in paths {
+            if self.stopped() {
+                break;
+            }
+            let metadata = match fs::symlink_metadata(&path) {
+                Ok(m) => m,
+                Err(err) => {
+                    self.scan_listener.error(&path, &err);
+                    eprintln!("{}: {}", path.display(), err);
+                    self.skip(&path, SkipReason::Error);
+                    continue;
+                },
+            };
+            if metadata.is_dir() {
+                self.skip(&path, SkipReason::Special);
+                continue;
+            }
+            let path = path.into_boxed_path();
+            if let Err(err) = self.add(path.clone(), &metadata) {
+                self.scan_listener.error(&path, &err);
+                eprintln!("{}: {}", path.display(), err);
+            }
+        }
+    }
+
+    /// Like `add_files`, but the files are compared and linked only with each other, and not with any other files.
+    /// E.g. for groups from a saved report, which have to be checked again, but shouldn't grow.
+    pub fn add_group(&mut self, paths: impl IntoIterator<Item = PathBuf>) {
+        self.groups_added += 1;
+        self.group = self.groups_added;
+        self.add_files(paths);
+        self.group = 0;
+    }
+
+    /// State of the scan that can be saved and resumed later, e.g. after `flush()` has been interrupted
+    pub fn checkpoint(&self) -> Checkpoint {
+        let mut files: Vec<_> = self.by_inode.values().flat_map(|set| set.borrow().paths.iter().map(|path| self.absolute(path)).collect::<Vec<_>>()).collect();
+        files.sort();
+        Checkpoint {
+            roots: self.roots.iter().map(|root| self.absolute(root)).collect(),
+            files,
+            dirs: self.to_scan.iter().map(|(_, dir)| self.absolute(dir)).collect(),
+        }
+    }
+
+    /// Continues a scan saved with `checkpoint()`. Call `flush()` afterwards.
+    /// Files and directories that don't exist any more are skipped.
+    pub fn resume(&mut self, checkpoint: Checkpoint) -> io::Result<()> {
+        for root in checkpoint.roots {
+            if let Ok(canonical) = fs::canonicalize(&root) {
+                self.canonical_roots.insert(canonical.into_boxed_path());
+            }
+            self.roots.push(root.into_boxed_path());
+        }
+        for path in checkpoint.files.into_iter().chain(checkpoint.dirs) {
+            let metadata = match fs::symlink_metadata(&path) {
+                Ok(m) => m,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            self.add(path.into_boxed_path(), &metadata)?;
+        }
+        Ok(())
+    }
+
+    /// Totals so far. They're final once `flush()` returns.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Paths that have been enqueued for scanning, as they were given
+    pub fn roots(&self) -> &[Box<Path>] {
+        &self.roots
+    }
+
+    /// Paths are kept as they were given, e.g. relative, for display. Files that are read later need them absolute.
+    pub fn absolute(&self, path: &Path) -> PathBuf {
+        undo::absolute(&self.cwd, path)
+    }
+
+    /// Path relative to the (innermost) scan root it's in
+    fn root_relative<'a>(&self, path: &'a Path) -> Option<&'a Path> {
+        root_relative(&self.roots, path)
+    }
+
+    /// True if the scan has been interrupted or has reached one of its limits.
+    /// Files found so far are still deduped, but no new files are scanned.
+    pub fn stopped(&self) -> bool {
+        self.settings.breaks() > 0 || self.limit_reached().is_some()
+    }
+
+    fn limit_reached(&self) -> Option<&'static str> {
+        if self.settings.max_runtime.map_or(false, |max| self.started.elapsed() >= max) {
+            return Some("maximum runtime");
+        }
+        if self.settings.stop_after_saving.map_or(false, |max| self.stats.bytes_deduplicated as u64 >= max) {
+            return Some("savings limit");
+        }
+        if self.settings.stop_after_groups.map_or(false, |max| self.dupe_groups >= max) {
+            return Some("limit of duplicate groups");
+        }
+        None
+    }
+
     /// Drains the queue of directories to scan
     pub fn flush(&mut self) -> io::Result<()> {
         let start_time = Instant::now();
+        if self.settings.count_first {
+            self.scan_listener.phase_started(Phase::Counting);
+            let (files, bytes) = self.count_queued();
+            let files = files + self.stats.added + self.stats.skipped;
+            self.scan_listener.totals_counted(files, bytes);
+        }
+        self.scan_listener.phase_started(Phase::Scanning);
+        let mut dirs_scanned = 0;
         while let Some((_, path)) = self.to_scan.pop() {
             if let Err(err) = self.scan_dir(&path) {
+                self.scan_listener.error(&path, &err);
                 eprintln!("Error scanning {}: {}", path.display(), err);
-                self.stats.skipped += 1;
+                self.skip(&path, SkipReason::Error);
             }
+            dirs_scanned += 1;
+            self.scan_listener.phase_progress(Phase::Scanning, dirs_scanned, dirs_scanned + self.to_scan.len());
             if self.settings.breaks() > 0 {
                 eprintln!("Stopping scan");
                 break;
             }
+            if let Some(reason) = self.limit_reached() {
+                eprintln!("Stopping scan: reached {}", reason);
+                break;
+            }
         }
         self.flush_deferred();
+        self.find_manifest_dupes();
         let scan_duration = Instant::now().duration_since(start_time);
         self.scan_listener.scan_over(self, &self.stats, scan_duration);
         Ok(())
@@ -169,26 +919,126 @@ impl Scanner {
         // and it'd be annoying if that aborted the whole operation.
         // FIXME: store the errors somehow to report them in a controlled manner
         for entry in fs::read_dir(path)?.filter_map(|p| p.ok()) {
-            if self.settings.breaks() > 0 {
+            if self.stopped() {
                 break;
             }
 
             let path = entry.path();
-            if let Some(file_name) = path.file_name() {
-                if self.exclude.contains(file_name) {
-                    self.stats.skipped += 1;
-                    continue;
+            if let Some(is_stale) = temp_file_status(&entry.file_name()) {
+                // Never dedupe own temporary files. Ones left after a crash are hardlinks or copies, and can be removed.
+                if is_stale && self.settings.run_mode == RunMode::Hardlink {
+                    if let Err(err) = fs::remove_file(&path) {
+                        self.scan_listener.error(&path, &err);
+                        eprintln!("Can't remove stale temporary file {}: {}", path.display(), err);
+                    }
                 }
+                self.skip(&path, SkipReason::Excluded);
+                continue;
+            }
+            let is_dir = || entry.file_type().map_or(false, |t| t.is_dir());
+            if self.settings.run_mode == RunMode::Trash && is_dir() && self.is_trash_dir(&path) {
+                self.skip(&path, SkipReason::Excluded);
+                continue;
+            }
+            if self.is_excluded(&path, is_dir) || (is_dir() && self.is_skipped_snapshot(&entry)) {
+                self.skip(&path, SkipReason::Excluded);
+                continue;
             }
             if let Err(err) = self.add(path.into_boxed_path(), &entry.metadata()?) {
+                self.scan_listener.error(&entry.path(), &err);
                 eprintln!("{}: {}", entry.path().display(), err);
             }
         }
         Ok(())
     }
 
+    /// Checks names and patterns excluded by the user. `is_dir` is called only if needed.
+    fn is_excluded(&self, path: &Path, is_dir: impl FnOnce() -> bool) -> bool {
+        if path.file_name().map_or(false, |file_name| self.exclude.contains(OsStr::from_bytes(&exclude::normalized(file_name.as_bytes())))) {
+            return true;
+        }
+        if self.exclude_rules.is_empty() && !self.settings.default_excludes {
+            return false;
+        }
+        let rel = match self.root_relative(path) {
+            Some(rel) => rel,
+            None => return false,
+        };
+        let is_dir = is_dir();
+        self.exclude_rules.first_match(rel, is_dir)
+            .or_else(|| self.default_exclude_rules.first_match(rel, is_dir).filter(|_| self.settings.default_excludes))
+            .unwrap_or(false)
+    }
+
+    fn is_skipped_snapshot(&self, entry: &fs::DirEntry) -> bool {
+        !self.settings.scan_snapshots && snapshot::is_snapshot_dir(&entry.path(), entry.ino())
+    }
+
+    /// Files moved to the trash must not be found again
+    fn is_trash_dir(&self, path: &Path) -> bool {
+        if trash::is_trash_dir(path) {
+            return true;
+        }
+        self.settings.trash_dir.as_ref().map_or(false, |dir| {
+            dir.file_name() == path.file_name() && fs::canonicalize(dir).ok() == fs::canonicalize(path).ok()
+        })
+    }
+
+    /// Quickly walks all queued directories to count files (that will be added or skipped) and their total size.
+    /// It's an approximation, since it doesn't know which files will be hardlinks.
+    fn count_queued(&self) -> (usize, u64) {
+        let mut files = 0;
+        let mut bytes = 0;
+        let mut dirs: Vec<_> = self.to_scan.iter().map(|(_, path)| path.to_path_buf()).collect();
+        while let Some(dir) = dirs.pop() {
+            if self.settings.breaks() > 0 {
+                break;
+            }
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            for entry in entries.filter_map(|e| e.ok()) {
+                let path = entry.path();
+                let ty = match entry.file_type() {
+                    Ok(ty) => ty,
+                    Err(_) => continue,
+                };
+                if self.is_excluded(&path, || ty.is_dir()) || (ty.is_dir() && self.is_skipped_snapshot(&entry)) {
+                    files += 1;
+                } else if ty.is_dir() {
+                    dirs.push(path);
+                } else {
+                    files += 1;
+                    if ty.is_file() {
+                        bytes += entry.metadata().map_or(0, |m| m.len());
+                    }
+                }
+            }
+        }
+        (files, bytes)
+    }
+
+    fn print_status(&self, current_path: &Path) {
+        eprintln!("Status: {:?}. {} directories queued, {} deferred. Current: {}",
+            self.stats, self.to_scan.len(), self.deferred_count, current_path.display());
+    }
+
+    fn skip(&mut self, path: &Path, reason: SkipReason) {
+        self.stats.skip(reason);
+        self.scan_listener.skipped(path, reason);
+    }
+
     fn add(&mut self, path: Box<Path>, metadata: &fs::Metadata) -> io::Result<()> {
         self.scan_listener.file_scanned(&path, &self.stats);
+        if self.settings.status_requested() {
+            self.print_status(&path);
+        }
+
+        if self.settings.filter.as_ref().map_or(false, |filter| !(filter.0)(&path, metadata)) {
+            self.skip(&path, SkipReason::Excluded);
+            return Ok(());
+        }
 
         let ty = metadata.file_type();
         if ty.is_dir() {
@@ -201,23 +1051,43 @@ impl Scanner {
         } else if ty.is_symlink() || !ty.is_file() {
             // Support for traversing symlinks would require preventing loops
             // Deduping /dev/ would be funny
-            self.stats.skipped += 1;
+            self.skip(&path, if ty.is_symlink() { SkipReason::Symlink } else { SkipReason::Special });
+            return Ok(());
+        }
+
+        if self.settings.is_excluded_by_age(metadata, self.created) || self.settings.is_excluded_by_owner(metadata)
+            || self.settings.max_links.map_or(false, |max| metadata.nlink() >= max) {
+            self.skip(&path, SkipReason::Excluded);
             return Ok(());
         }
 
-        // APFS reports 4*MB* block size
-        let small_size = cmp::min(16 * 1024, metadata.blksize());
-        if metadata.size() == 0 || (self.settings.ignore_small && metadata.size() < small_size) {
-            self.stats.skipped += 1;
+        let small_size = *self.block_sizes.entry(metadata.dev()).or_insert_with(|| {
+            // blksize is the preferred I/O size rather than the allocation unit. APFS reports 4*MB*.
+            fs_block_size(&path).unwrap_or_else(|_| cmp::min(16 * 1024, metadata.blksize()))
+        });
+        let too_small = if metadata.size() == 0 { !self.settings.include_empty } else { self.settings.ignore_small && metadata.size() < small_size };
+        if too_small {
+            self.skip(&path, SkipReason::TooSmall);
+            return Ok(());
+        }
+        if self.settings.skip_open_files && self.settings.run_mode != RunMode::DryRunNoMerging && is_open_for_writing(&path) {
+            eprintln!("{}: skipped, because it's open for writing", path.display());
+            self.skip(&path, SkipReason::OpenForWriting);
             return Ok(());
         }
+        if self.settings.tune_for_hard_disks {
+            self.rotational.entry(metadata.dev()).or_insert_with(|| metadata::is_rotational(metadata.dev()));
+        }
         self.stats.added += 1;
+        self.stats.bytes_added += metadata.size() as usize;
+        self.stats.count(root_index(&self.roots, &path), metadata.dev(), |s| s.added += 1);
 
         if let Some(fileset) = self.new_fileset(&path, metadata) {
-            self.dedupe_by_content(fileset, path, metadata)?;
+            self.dedupe_by_size(fileset, path, metadata)?;
         } else {
             self.stats.hardlinks += 1;
             self.stats.bytes_saved_by_hardlinks += metadata.size() as usize;
+            self.stats.allocated_bytes_saved_by_hardlinks += allocated_size(metadata) as usize;
         }
         Ok(())
     }
@@ -244,10 +1114,67 @@ impl Scanner {
         }
     }
 
+    fn dedupe_by_size(&mut self, fileset: RcFileSet, path: Box<Path>, metadata: &fs::Metadata) -> io::Result<()> {
+        let m = Metadata::new(metadata).with_mount_id(self.mount_id(&path)).with_group(self.group);
+        match self.by_size.entry(m) {
+            HashEntry::Vacant(e) => {
+                e.insert(Some(fileset));
+                return Ok(());
+            },
+            HashEntry::Occupied(mut e) => {
+                if let Some(first) = e.get_mut().take() {
+                    // It's the first file of that size, so it's unique in by_content too
+                    let first_path = first.borrow().paths[0].clone();
+                    self.stats.bytes_compared += m.size as usize;
+                    self.by_content.insert(self.file_content(first_path, m), vec![first]);
+                }
+            },
+        }
+        self.stats.bytes_compared += m.size as usize;
+        let read_before = lazyfile::bytes_read();
+        let res = self.dedupe_by_content(fileset, path, m, allocated_size(metadata));
+        self.stats.bytes_read += (lazyfile::bytes_read() - read_before) as usize;
+        res
+    }
+
+    /// Mount of the file's directory. Files are usually added directory by directory, so the last one is cached.
+    fn mount_id(&mut self, path: &Path) -> Option<u64> {
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        match &self.last_mount_id {
+            Some((last_dir, mount_id)) if **last_dir == *dir => *mount_id,
+            _ => {
+                let mount_id = ExtendedMetadata::from_path(dir).ok().and_then(|m| m.mount_id);
+                self.last_mount_id = Some((dir.into(), mount_id));
+                mount_id
+            },
+        }
+    }
+
+    fn file_content(&self, path: Box<Path>, m: Metadata) -> FileContent {
+        let mut content = if !self.manifest.is_empty() {
+            let known = self.manifest.hash_of(&path);
+            FileContent::with_known_hash(path, m, known)
+        } else if self.settings.full_hashes {
+            FileContent::with_full_hash(path, m)
+        } else {
+            FileContent::new(path, m)
+        };
+        if self.rotational.get(&m.dev) == Some(&true) {
+            content.set_read_options(ReadOptions { prefetch: true, ..self.settings.read_options });
+            content.set_chunk_sizes(self.settings.chunk_sizes.for_hard_disk());
+        } else {
+            content.set_read_options(self.settings.read_options);
+            content.set_chunk_sizes(self.settings.chunk_sizes);
+        }
+        content
+    }
+
     /// Here's where all the magic happens
-    fn dedupe_by_content(&mut self, fileset: RcFileSet, path: Box<Path>, metadata: &fs::Metadata) -> io::Result<()> {
+    fn dedupe_by_content(&mut self, fileset: RcFileSet, path: Box<Path>, m: Metadata, allocated_size: u64) -> io::Result<()> {
         let mut deferred = false;
-        match self.by_content.entry(FileContent::new(path, Metadata::new(metadata))) {
+        let root = root_index(&self.roots, &path);
+        let extension = path.extension().map(|ext| ext.to_string_lossy().to_lowercase()).unwrap_or_default();
+        match self.by_content.entry(self.file_content(path, m)) {
             BTreeEntry::Vacant(e) => {
                 // Seems unique so far
                 e.insert(vec![fileset]);
@@ -255,14 +1182,25 @@ impl Scanner {
             BTreeEntry::Occupied(mut e) => {
                 // Found a dupe!
                 self.stats.dupes += 1;
-                self.stats.bytes_deduplicated += metadata.size() as usize;
+                self.stats.bytes_deduplicated += m.size as usize;
+                self.stats.allocated_bytes_deduplicated += allocated_size as usize;
+                self.stats.count(root, m.dev, |s| {
+                    s.dupes += 1;
+                    s.bytes_deduplicated += m.size as usize;
+                });
+                *self.stats.bytes_by_extension.entry(extension).or_insert(0) += m.size as usize;
                 let filesets = e.get_mut();
                 filesets.push(fileset);
+                if filesets.len() == 2 {
+                    self.dupe_groups += 1;
+                }
                 // Deduping can either be done immediately or later. Immediate is more cache-friendly and interactive,
                 // but for files that already have hardlinks it can cause unnecessary re-linking. So if there are
                 // hardlinks in the set, wait until the end to dedupe when all hardlinks are known.
                 if filesets.iter().all(|set| set.borrow().links() == 1) {
-                    Self::dedupe(filesets, self.settings.run_mode, &mut *self.scan_listener)?;
+                    let is_protected = |path: &Path| self.protected.level(&self.roots, path);
+                    let in_scope = |path: &Path| is_in_roots(&self.roots, &self.canonical_roots, path);
+                    Self::dedupe(filesets, &self.settings, &self.roots, &is_protected, &in_scope, &mut *self.scan_listener, self.undo_log.as_mut())?;
                 } else {
                     deferred = true;
                 }
@@ -278,31 +1216,86 @@ impl Scanner {
                 self.next_deferred_count *= 2;
                 self.deferred_count = 0;
                 self.flush_deferred();
+                self.scan_listener.phase_started(Phase::Scanning);
             }
         }
         Ok(())
     }
 
     fn flush_deferred(&mut self) {
-        for filesets in self.by_content.values_mut() {
+        self.scan_listener.phase_started(Phase::LinkingDeferred);
+        let is_protected = |path: &Path| self.protected.level(&self.roots, path);
+        let in_scope = |path: &Path| is_in_roots(&self.roots, &self.canonical_roots, path);
+        let total = self.by_content.len();
+        for (done, filesets) in self.by_content.values_mut().enumerate() {
             if self.settings.breaks() > 1 {
                 eprintln!("Aborting");
                 break;
             }
-            if let Err(err) = Self::dedupe(filesets, self.settings.run_mode, &mut *self.scan_listener) {
+            // Paths of failed files are dropped, so this has to be known beforehand
+            let group_path = filesets.iter().find_map(|set| set.borrow().paths.first().cloned());
+            if let Err(err) = Self::dedupe(filesets, &self.settings, &self.roots, &is_protected, &in_scope, &mut *self.scan_listener, self.undo_log.as_mut()) {
+                if let Some(path) = group_path {
+                    self.scan_listener.error(&path, &err);
+                }
                 eprintln!("{}", err);
             }
+            self.scan_listener.phase_progress(Phase::LinkingDeferred, done + 1, total);
         }
     }
 
-    fn dedupe(filesets: &mut [RcFileSet], run_mode: RunMode, scan_listener: &mut dyn ScanListener) -> io::Result<()> {
-        if run_mode == RunMode::DryRunNoMerging {
+    /// With `isolate_roots`, the filesets are merged in groups that have at most one file from each root
+    fn dedupe(filesets: &mut [RcFileSet], settings: &Settings, roots: &[Box<Path>], is_protected: &dyn Fn(&Path) -> Protection, in_scope: &dyn Fn(&Path) -> bool, scan_listener: &mut dyn ScanListener, mut undo_log: Option<&mut UndoLog>) -> io::Result<()> {
+        if settings.run_mode == RunMode::DryRunNoMerging {
             return Ok(());
         }
+        if !settings.isolate_roots {
+            return Self::merge(filesets, settings, roots, is_protected, in_scope, scan_listener, undo_log);
+        }
+
+        // Sets that have been merged before contain paths from multiple roots, and are kept together.
+        // Other sets join the first group that doesn't have their root yet.
+        let mut groups: Vec<(Vec<RcFileSet>, HashSet<usize>)> = Vec::new();
+        for set in filesets.iter() {
+            let set_roots: HashSet<usize> = set.borrow().paths.iter().filter_map(|path| root_index(roots, path)).collect();
+            if set_roots.is_empty() {
+                continue;
+            }
+            match groups.iter_mut().find(|(_, group_roots)| group_roots.is_disjoint(&set_roots)) {
+                Some((sets, group_roots)) => {
+                    sets.push(Rc::clone(set));
+                    group_roots.extend(set_roots);
+                },
+                None => groups.push((vec![Rc::clone(set)], set_roots)),
+            }
+        }
+        for (mut sets, _) in groups {
+            if sets.len() > 1 {
+                Self::merge(&mut sets, settings, roots, is_protected, in_scope, scan_listener, undo_log.as_deref_mut())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Protected files can only be the source, and files in reference roots are preferred. Sets with protected files are left alone.
+    /// Files for which `in_scope` is false are never replaced.
+    fn merge(filesets: &mut [RcFileSet], settings: &Settings, roots: &[Box<Path>], is_protected: &dyn Fn(&Path) -> Protection, in_scope: &dyn Fn(&Path) -> bool, scan_listener: &mut dyn ScanListener, mut undo_log: Option<&mut UndoLog>) -> io::Result<()> {
+        let protected: Vec<Protection> = filesets.iter()
+            .map(|set| set.borrow().paths.iter().map(|path| is_protected(path)).max().unwrap_or(Protection::None))
+            .collect();
+        let most_protected = protected.iter().copied().max().unwrap_or(Protection::None);
 
-        // Find file with the largest number of hardlinks, since it's less work to merge a small group into a large group
+        // Find file with the largest number of hardlinks, since it's less work to merge a small group into a large group.
+        // With `Keep::Oldest` the age matters first.
+        let keep_key = |fileset: &FileSet| match settings.keep {
+            Keep::MostLinks => None,
+            Keep::Oldest => fileset.paths.first()
+                .and_then(|path| fs::symlink_metadata(path).ok())
+                .and_then(|m| m.modified().ok())
+                .map(cmp::Reverse),
+        };
         let mut largest_idx = 0;
-        let mut largest_links = 0;
+        let mut largest_key = (None, 0);
         let mut nonempty_filesets = 0;
         for (idx, fileset) in filesets.iter().enumerate() {
             let fileset = fileset.borrow();
@@ -310,10 +1303,13 @@ impl Scanner {
                 // Only actual paths we can merge matter here
                 nonempty_filesets += 1;
             }
-            let links = fileset.links();
-            if links > largest_links {
+            if protected[idx] != most_protected {
+                continue;
+            }
+            let key = (keep_key(&fileset), fileset.links());
+            if key > largest_key {
                 largest_idx = idx;
-                largest_links = links;
+                largest_key = key;
             }
         }
 
@@ -322,60 +1318,522 @@ impl Scanner {
         }
 
         // The set is still going to be in use! So everything has to be updated to make sense for the next call
-        let merged_paths = &mut { filesets[largest_idx].borrow_mut() }.paths;
-        let source_path = merged_paths[0].clone();
+        // If the source can't have any more links, the set that becomes the new source is the merge target
+        let mut target = Rc::clone(&filesets[largest_idx]);
+        let mut source_path = target.borrow().paths[0].clone();
+        // For `settings.linked_mtime`, once it's known
+        let mut source_mtime = None;
         for (i, set) in filesets.iter().enumerate() {
             // We don't want to merge the set with itself
             if i == largest_idx {
                 continue;
             }
 
-            let paths = &mut set.borrow_mut().paths;
+            let dest_paths = {
+                let paths = &mut set.borrow_mut().paths;
+                // Protected paths stay in their set, but their unprotected hardlinks can still be moved to the source
+                if protected[i].is_replaceable() {
+                    std::mem::take(paths)
+                } else {
+                    let (kept, dest_paths) = std::mem::take(paths).into_iter().partition(|path| !is_protected(path).is_replaceable());
+                    *paths = kept;
+                    dest_paths
+                }
+            };
+            let mut is_new_source = false;
             // dest_path will be "lost" on error, but that's fine, since we don't want to dedupe it if it causes errors
-            for dest_path in paths.drain(..) {
+            for dest_path in dest_paths {
+                if is_new_source {
+                    // Other paths in this set are hardlinks of the new source already
+                    target.borrow_mut().paths.push(dest_path);
+                    continue;
+                }
                 assert_ne!(&source_path, &dest_path);
                 debug_assert_ne!(fs::symlink_metadata(&source_path)?.ino(), fs::symlink_metadata(&dest_path)?.ino());
 
-                if run_mode == RunMode::DryRun {
+                if !settings.ignore_selinux && !same_selinux_context(&source_path, &dest_path) {
+                    eprintln!("{}: not replaced, because its SELinux context differs from {}", dest_path.display(), source_path.display());
+                    continue;
+                }
+                if settings.require_same_xattrs && !same_xattrs(&source_path, &dest_path) {
+                    eprintln!("{}: not replaced, because its extended attributes differ from {}", dest_path.display(), source_path.display());
+                    continue;
+                }
+                // Linking or renaming them would fail half-way. Checked only here, since it needs another stat of every file.
+                if is_immutable(&dest_path) || is_immutable(&source_path) {
+                    eprintln!("{}: not replaced, because it or {} is immutable or append-only", dest_path.display(), source_path.display());
+                    continue;
+                }
+
+                if settings.run_mode == RunMode::DryRun {
                     scan_listener.duplicate_found(&dest_path, &source_path);
-                    merged_paths.push(dest_path);
+                    target.borrow_mut().paths.push(dest_path);
+                    continue;
+                }
+
+                // Last line of defense against bugs and symlink tricks
+                if !in_scope(&dest_path) {
+                    let err = io::Error::new(io::ErrorKind::PermissionDenied, "not inside any of the scanned directories");
+                    eprintln!("refusing to replace {}: {}", dest_path.display(), err);
+                    scan_listener.error(&dest_path, &err);
+                    continue;
+                }
+
+                if settings.is_modified_too_recently(&dest_path) || settings.is_modified_too_recently(&source_path) {
+                    eprintln!("{}: not replaced, because it or {} has been modified recently", dest_path.display(), source_path.display());
+                    continue;
+                }
+
+                if settings.verify_before_link {
+                    match files_identical(&source_path, &dest_path) {
+                        Ok(true) => {},
+                        Ok(false) => {
+                            eprintln!("{}: not replaced, because it differs from {} (an imported hash is out of date)", dest_path.display(), source_path.display());
+                            continue;
+                        },
+                        Err(err) => {
+                            eprintln!("{}: not replaced, because it can't be compared with {}: {}", dest_path.display(), source_path.display(), err);
+                            scan_listener.error(&dest_path, &err);
+                            continue;
+                        },
+                    }
+                }
+
+                if scan_listener.before_link(&source_path, &dest_path) == Decision::Skip {
+                    continue;
+                }
+
+                if settings.run_mode == RunMode::Trash {
+                    // The file is gone from the set, since it's not in the scanned directory any more
+                    let moved = match &settings.trash_dir {
+                        Some(dir) => trash::move_to_dir(&dest_path, root_relative(roots, &dest_path).unwrap_or(&dest_path), dir),
+                        None => trash::move_to_trash(&dest_path),
+                    };
+                    match moved {
+                        Ok(trash_path) => scan_listener.trashed(&dest_path, &source_path, &trash_path),
+                        Err(err) => {
+                            // Other files may still be movable, e.g. if this one is on a mount without a trash directory
+                            eprintln!("unable to move {} to trash due to {}", dest_path.display(), err);
+                            scan_listener.error(&dest_path, &err);
+                        },
+                    }
                     continue;
                 }
 
-                let temp_path = dest_path.with_file_name(".tmp-dupe-e1iIQcBFn5pC4MUSm-xkcd-221");
+                if settings.grant_read_access {
+                    if let Err(err) = grant_read_access(&source_path, &dest_path) {
+                        eprintln!("unable to give owner of {} access to {} due to {}", dest_path.display(), source_path.display(), err);
+                        scan_listener.error(&dest_path, &err);
+                        continue;
+                    }
+                }
+
+                let temp_path = temp_path(&dest_path);
                 debug_assert!(!temp_path.exists());
                 debug_assert!(source_path.exists());
                 debug_assert!(dest_path.exists());
 
                 // In posix link guarantees not to overwrite, and mv guarantes to move atomically
                 // so this two-step replacement is pretty robust
-                if let Err(err) = fs::hard_link(&source_path, &temp_path) {
+                if let Err(err) = retry(settings.link_retries, || fs::hard_link(&source_path, &temp_path)) {
+                    let _ = fs::remove_file(&temp_path);
+                    // Filesystems limit the number of links (LINK_MAX). The file stays as-is, and starts a new group.
+                    if err.raw_os_error() == Some(libc::EMLINK) {
+                        source_path = dest_path.clone();
+                        source_mtime = None;
+                        target = Rc::clone(set);
+                        target.borrow_mut().paths.push(dest_path);
+                        is_new_source = true;
+                        continue;
+                    }
+                    // Every other file on such filesystem would fail the same way
+                    if is_unsupported_link_error(&err) {
+                        print_link_error_hint(&dest_path, &err);
+                        scan_listener.error(&dest_path, &err);
+                        continue;
+                    }
                     eprintln!("unable to hardlink {} {} due to {}", source_path.display(), temp_path.display(), err);
-                    let _ = fs::remove_file(temp_path);
                     return Err(err);
                 }
-                if let Err(err) = fs::rename(&temp_path, &dest_path) {
+                let dest_mtime = if settings.linked_mtime != LinkedMtime::Unchanged {
+                    fs::symlink_metadata(&dest_path).and_then(|m| m.modified()).ok()
+                } else {
+                    None
+                };
+                // Logged before the file is replaced, so that an interrupted replacement is still in the log
+                if let Some(undo_log) = undo_log.as_mut() {
+                    let res = fs::symlink_metadata(&dest_path).and_then(|m| undo_log.record(&source_path, &dest_path, &m));
+                    if let Err(err) = res {
+                        let _ = fs::remove_file(temp_path);
+                        return Err(err);
+                    }
+                }
+                if let Err(err) = retry(settings.link_retries, || fs::rename(&temp_path, &dest_path)) {
                     eprintln!("unable to rename {} {} due to {}", temp_path.display(), dest_path.display(), err);
                     let _ = fs::remove_file(temp_path);
                     return Err(err);
                 }
+                // A crash can't leave the destination missing, but without this the change could be lost
+                if let Err(err) = sync_parent_dir(&dest_path) {
+                    eprintln!("unable to sync directory of {} due to {}", dest_path.display(), err);
+                }
+                if let Some(dest_mtime) = dest_mtime {
+                    let current = source_mtime.or_else(|| fs::symlink_metadata(&source_path).and_then(|m| m.modified()).ok());
+                    if let Some(current) = current {
+                        let mtime = settings.linked_mtime.pick(current, dest_mtime);
+                        source_mtime = Some(current);
+                        if mtime != current {
+                            match set_mtime(&source_path, mtime) {
+                                Ok(()) => source_mtime = Some(mtime),
+                                Err(err) => eprintln!("unable to set modification time of {} due to {}", source_path.display(), err),
+                            }
+                        }
+                    }
+                }
                 debug_assert!(!temp_path.exists());
                 debug_assert!(source_path.exists());
                 debug_assert!(dest_path.exists());
                 scan_listener.hardlinked(&dest_path, &source_path);
-                merged_paths.push(dest_path);
+                target.borrow_mut().paths.push(dest_path);
             }
         }
         Ok(())
     }
 
+    /// Paths of files that have been compared and found identical, in groups of 2 or more (including their hardlinks).
+    /// Files that are only hardlinks of each other aren't duplicates, so they're not included.
+    pub fn dupe_paths(&self) -> impl Iterator<Item = Vec<Box<Path>>> + '_ {
+        // Sets that have been merged are left empty, but are still in the group
+        self.by_content.values().filter(|filesets| filesets.len() > 1).filter_map(|filesets| {
+            let paths: Vec<_> = filesets.iter().flat_map(|set| set.borrow().paths.clone()).collect();
+            if paths.len() > 1 { Some(paths) } else { None }
+        })
+    }
+
+    /// Writes BLAKE3 hashes of all scanned files in the format of `b3sum`, sorted by path, with their sizes and modification times in comments.
+    /// Identical files are hashed only once, and with `Settings::full_hashes` only the parts not read for comparisons are read again.
+    pub fn export_hashes(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut lines = Vec::new();
+        for (hash, paths) in self.hashed_groups() {
+            match hash {
+                Ok(hash) => lines.extend(paths.into_iter().map(|path| (path, hash))),
+                Err(err) => for path in paths {
+                    eprintln!("Can't hash {}: {}", path.display(), err);
+                },
+            }
+        }
+        lines.sort_by(|a, b| a.0.cmp(&b.0));
+        manifest::write_header(out)?;
+        for (path, hash) in lines {
+            // Files modified during the scan may have been hashed before the change, so they're not marked as unchanged
+            if let Ok(m) = fs::symlink_metadata(&path) {
+                if m.modified().map_or(false, |mtime| mtime < self.created) {
+                    manifest::write_stat(out, &m)?;
+                }
+            }
+            manifest::write_line(out, &hash, &path)?;
+        }
+        out.flush()
+    }
+
+    /// Reports files identical to ones from an imported manifest that haven't been scanned, because they're not on this machine
+    fn find_manifest_dupes(&mut self) {
+        if !self.manifest.has_remote() {
+            return;
+        }
+        self.scan_listener.phase_started(Phase::MatchingManifest);
+        let mut found = Vec::new();
+        let mut errors = Vec::new();
+        for (hash, paths) in self.hashed_groups() {
+            match hash {
+                Ok(hash) => for remote in self.manifest.remote_paths(&hash) {
+                    found.push((paths[0].clone(), remote.clone()));
+                },
+                Err(err) => errors.push((paths[0].clone(), err)),
+            }
+        }
+        for (path, err) in errors {
+            self.scan_listener.error(&path, &err);
+            eprintln!("Can't hash {}: {}", path.display(), err);
+        }
+        for (path, remote) in found {
+            self.scan_listener.duplicate_in_manifest(&path, &remote);
+        }
+    }
+
+    /// Hashes of whole files, with paths of all files that have that content. Identical files are hashed only once.
+    fn hashed_groups(&self) -> impl Iterator<Item = (io::Result<blake3::Hash>, Vec<Box<Path>>)> + '_ {
+        let paths = |filesets: &[RcFileSet]| -> Vec<Box<Path>> {
+            filesets.iter().flat_map(|set| set.borrow().paths.clone()).collect()
+        };
+        let same_size = self.by_content.iter().map(move |(content, filesets)| (content.full_hash(), paths(filesets)));
+        let unique_sizes = self.by_size.values().flatten().map(move |fileset| {
+            let paths = paths(std::slice::from_ref(fileset));
+            let hash = self.manifest.hash_of(&paths[0]).map_or_else(|| {
+                let mut hasher = Hasher::new();
+                hasher.set_read_options(self.settings.read_options);
+                hasher.full_hash(&paths[0])
+            }, Ok);
+            (hash, paths)
+        });
+        same_size.chain(unique_sizes).filter(|(_, paths)| !paths.is_empty())
+    }
+
+    /// All files grouped by content, including groups of just one file
     pub fn dupes(&self) -> Vec<Vec<FileSet>> {
-        self.by_content.values().map(|filesets| {
-            filesets.iter().map(|d|{
+        self.dupes_iter().collect()
+    }
+
+    /// Files that are hardlinks of each other (including links made by this scan), sorted by path.
+    /// `FileSet::max_hardlinks` tells if there are more links outside of the scanned directories.
+    pub fn hardlink_groups(&self) -> Vec<FileSet> {
+        let mut groups: Vec<_> = self.by_inode.values()
+            .filter(|set| set.borrow().paths.len() > 1)
+            .map(|set| set.borrow().clone())
+            .collect();
+        groups.sort_unstable_by(|a, b| a.paths[0].cmp(&b.paths[0]));
+        groups
+    }
+
+    /// Calls `f` with the size of the files and the sets of every group, including unique files, without copying them like `dupes_iter()`
+    pub(crate) fn for_each_group(&self, mut f: impl FnMut(u64, &[&FileSet])) {
+        for (content, filesets) in &self.by_content {
+            let sets: Vec<_> = filesets.iter().map(|set| set.borrow()).collect();
+            f(content.size(), &sets.iter().map(|set| &**set).collect::<Vec<_>>());
+        }
+        for (m, fileset) in &self.by_size {
+            if let Some(set) = fileset {
+                f(m.size, &[&set.borrow()]);
+            }
+        }
+    }
+
+    /// Same as `dupes()`, but copies one group at a time, which needs less memory for large scans
+    pub fn dupes_iter(&self) -> impl Iterator<Item = Vec<FileSet>> + '_ {
+        self.dupes_with_content_iter().map(|(_, sets)| sets)
+    }
+
+    /// Same as `dupes_iter()`, with the content of groups that have been compared with other files, e.g. for `FileContent::full_hash()`
+    pub fn dupes_with_content_iter(&self) -> impl Iterator<Item = (Option<&FileContent>, Vec<FileSet>)> + '_ {
+        let unique_sizes = self.by_size.values().flatten().map(|fileset| (None, vec![fileset.borrow().clone()]));
+        self.by_content.iter().map(|(content, filesets)| {
+            (Some(content), filesets.iter().map(|d|{
                 let tmp = d.borrow();
                 (*tmp).clone()
-            }).collect()
-        }).collect()
+            }).collect())
+        }).chain(unique_sizes)
+    }
+}
+
+/// Repeats the operation after errors that may go away by themselves, waiting longer each time
+fn retry(retries: u32, mut op: impl FnMut() -> io::Result<()>) -> io::Result<()> {
+    let mut delay = Duration::from_millis(50);
+    for _ in 0..retries {
+        match op() {
+            Err(err) if matches!(err.raw_os_error(), Some(libc::EBUSY | libc::ESTALE | libc::EAGAIN | libc::EINTR | libc::ETIMEDOUT)) => {
+                std::thread::sleep(delay);
+                delay *= 2;
+            },
+            res => return res,
+        }
+    }
+    op().map_err(|err| if retries > 0 { io::Error::new(err.kind(), format!("{} (after {} retries)", err, retries)) } else { err })
+}
+
+/// Files that can be used as link sources, but are never replaced
+#[derive(Debug, Default)]
+struct Protected {
+    /// Roots from `enqueue_reference`
+    reference_roots: Vec<Box<Path>>,
+    /// Roots from `enqueue_preferred`
+    preferred_roots: Vec<Box<Path>>,
+    /// From `keep_matching`
+    preferred_rules: ExcludeRules,
+    rules: ExcludeRules,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Protection {
+    None,
+    /// In a preferred root, or matches keep rules. Can still be replaced, but is chosen as the link source over unprotected files.
+    Preferred,
+    /// Matches protect rules
+    Rules,
+    /// In a reference root
+    Reference,
+}
+
+impl Protected {
+    fn level(&self, roots: &[Box<Path>], path: &Path) -> Protection {
+        if self.reference_roots.iter().any(|root| path.starts_with(root)) {
+            Protection::Reference
+        } else if !self.rules.is_empty() && root_relative(roots, path).map_or(false, |rel| self.rules.is_excluded(rel, false)) {
+            Protection::Rules
+        } else if self.preferred_roots.iter().any(|root| path.starts_with(root))
+            || (!self.preferred_rules.is_empty() && root_relative(roots, path).map_or(false, |rel| self.preferred_rules.is_excluded(rel, false))) {
+            Protection::Preferred
+        } else {
+            Protection::None
+        }
+    }
+}
+
+impl Protection {
+    fn is_replaceable(self) -> bool {
+        self <= Protection::Preferred
+    }
+}
+
+/// Path relative to the (innermost) root it's in
+fn root_relative<'a>(roots: &[Box<Path>], path: &'a Path) -> Option<&'a Path> {
+    roots.iter()
+        .filter_map(|root| path.strip_prefix(root).ok())
+        .min_by_key(|rel| rel.as_os_str().len())
+}
+
+/// Whether the path is in one of the roots, both as given and after resolving symlinks in its directory.
+/// Without roots every file has been given explicitly to `add_files`.
+fn is_in_roots(roots: &[Box<Path>], canonical_roots: &HashSet<Box<Path>>, path: &Path) -> bool {
+    if roots.is_empty() {
+        return true;
+    }
+    if root_index(roots, path).is_none() {
+        return false;
     }
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let canonical = match (fs::canonicalize(dir), path.file_name()) {
+        (Ok(dir), Some(name)) => dir.join(name),
+        _ => return false,
+    };
+    canonical_roots.iter().any(|root| canonical.starts_with(root))
 }
 
+/// Index of the innermost root that contains the path
+fn root_index(roots: &[Box<Path>], path: &Path) -> Option<usize> {
+    roots.iter().enumerate()
+        .filter(|(_, root)| path.starts_with(root))
+        .max_by_key(|(_, root)| root.as_os_str().len())
+        .map(|(i, _)| i)
+}
+
+/// Tries to take a read lease, which the kernel refuses if the file is open for writing anywhere (including this process).
+/// Leases aren't available for files owned by other users (without CAP_LEASE) or on network filesystems, and then it's assumed the file isn't open.
+#[cfg(target_os = "linux")]
+fn is_open_for_writing(path: &Path) -> bool {
+    use std::os::unix::io::AsRawFd;
+
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(_) => return false,
+    };
+    // Not in libc for all targets
+    const F_SETSIG: libc::c_int = 10;
+    let fd = file.as_raw_fd();
+    unsafe {
+        // If someone opened the file while the lease is held, the default SIGIO would kill the process. SIGURG is ignored by default.
+        libc::fcntl(fd, F_SETSIG, libc::SIGURG);
+        if libc::fcntl(fd, libc::F_SETLEASE, libc::F_RDLCK) == 0 {
+            libc::fcntl(fd, libc::F_SETLEASE, libc::F_UNLCK);
+            return false;
+        }
+    }
+    io::Error::last_os_error().raw_os_error() == Some(libc::EAGAIN)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_open_for_writing(_: &Path) -> bool {
+    false
+}
+
+thread_local! {
+    /// Devices and errors that `print_link_error_hint` has explained already
+    static LINK_ERROR_HINTS: RefCell<HashSet<(u64, i32)>> = RefCell::new(HashSet::new());
+}
+
+/// Errors that aren't specific to the file, but to the filesystem or mount it's on
+fn is_unsupported_link_error(err: &io::Error) -> bool {
+    err.raw_os_error().map_or(false, |e| e == libc::EXDEV || e == libc::EPERM || e == libc::ENOTSUP || e == libc::EOPNOTSUPP)
+}
+
+/// Prints an explanation once per filesystem, instead of the same error for every file on it
+fn print_link_error_hint(path: &Path, err: &io::Error) {
+    let errno = err.raw_os_error().unwrap_or(0);
+    let dev = fs::symlink_metadata(path).map_or(0, |m| m.dev());
+    if !LINK_ERROR_HINTS.with(|hints| hints.borrow_mut().insert((dev, errno))) {
+        return;
+    }
+    let reason = if errno == libc::EXDEV {
+        "the files are on different mounts, which can't have hardlinks to each other"
+    } else if errno == libc::EPERM {
+        "hardlinking is not permitted there. The filesystem may not support hardlinks (e.g. exFAT or FAT32), or the files may belong to another user"
+    } else {
+        "the filesystem doesn't support hardlinks"
+    };
+    eprintln!("Can't hardlink {}: {} ({}).\nOther files on this filesystem that fail the same way are skipped without a message. Consider excluding it from the scan.", path.display(), reason, err);
+}
+
+/// Owner of `dest` keeps read access after it's replaced with a link to `source`
+fn grant_read_access(source: &Path, dest: &Path) -> io::Result<()> {
+    let (source_metadata, dest_uid) = (fs::symlink_metadata(source)?, fs::symlink_metadata(dest)?.uid());
+    // Everyone can read it anyway
+    if source_metadata.uid() == dest_uid || source_metadata.mode() & 0o004 != 0 {
+        return Ok(());
+    }
+    acl::grant_read(source, dest_uid)
+}
+
+/// Files that can't be checked are treated as different
+fn same_selinux_context(a: &Path, b: &Path) -> bool {
+    match (metadata::selinux_context(a), metadata::selinux_context(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Files that can't be checked are treated as different
+fn same_xattrs(a: &Path, b: &Path) -> bool {
+    match (metadata::xattrs(a), metadata::xattrs(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Files that can't be checked will fail later anyway
+fn is_immutable(path: &Path) -> bool {
+    ExtendedMetadata::from_path(path).map_or(false, |m| m.is_immutable())
+}
+
+/// Leaves the access time as-is
+fn set_mtime(path: &Path, mtime: SystemTime) -> io::Result<()> {
+    let since_epoch = mtime.duration_since(SystemTime::UNIX_EPOCH).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "time before 1970"))?;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let times = [
+        libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+        libc::timespec { tv_sec: since_epoch.as_secs() as libc::time_t, tv_nsec: since_epoch.subsec_nanos() as _ },
+    ];
+    if unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Allocation unit of the filesystem the path is on. Files smaller than that can't save any space.
+fn fs_block_size(path: &Path) -> io::Result<u64> {
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // f_frsize may be 0 on old systems
+    let size = if stat.f_frsize > 0 { stat.f_frsize as u64 } else { stat.f_bsize as u64 };
+    if size == 0 {
+        return Err(io::Error::new(io::ErrorKind::Other, "unknown block size"));
+    }
+    // NFS reports its transfer size, which can be 1MB
+    Ok(cmp::min(size, 64 * 1024))
+}
+
+/// Space taken on disk. `st_blocks` is always in 512-byte units, regardless of the filesystem's block size.
+fn allocated_size(metadata: &fs::Metadata) -> u64 {
+    metadata.blocks() * 512
+}
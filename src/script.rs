@@ -0,0 +1,95 @@
+use crate::scanner::ScanListener;
+use crate::scanner::Scanner;
+use crate::scanner::Stats;
+use std::io;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::Duration;
+
+/// Instead of changing anything, prints a shell script that would perform the same hardlinking.
+/// Needs to run in `RunMode::DryRun` to see the same source files the real run would use.
+#[derive(Debug, Default)]
+pub struct ShellScriptOutput {
+    header_written: bool,
+}
+
+impl ShellScriptOutput {
+    pub fn new() -> Self {
+        ShellScriptOutput { header_written: false }
+    }
+
+    fn write_header(&mut self, out: &mut impl Write) -> io::Result<()> {
+        if !self.header_written {
+            self.header_written = true;
+            writeln!(out, "#!/bin/sh")?;
+            writeln!(out, "# Generated by dupe-krill {}. Review before running.", env!("CARGO_PKG_VERSION"))?;
+            writeln!(out, "set -eu")?;
+            writeln!(out)?;
+            writeln!(out, "# Same as dupe-krill: link to a temp file first, then atomically rename it over the dupe")?;
+            writeln!(out, "link() {{")?;
+            writeln!(out, "    ln -- \"$1\" \"$2.dupe-krill-tmp\" && mv -f -- \"$2.dupe-krill-tmp\" \"$2\"")?;
+            writeln!(out, "}}")?;
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+
+    fn write_link(&mut self, keep: &Path, replace: &Path) -> io::Result<()> {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        self.write_header(&mut out)?;
+        out.write_all(b"link ")?;
+        out.write_all(&shell_quote(keep))?;
+        out.write_all(b" ")?;
+        out.write_all(&shell_quote(replace))?;
+        out.write_all(b"\n")
+    }
+}
+
+/// Single-quotes the path for POSIX shells. Works with any bytes, not just UTF-8.
+pub(crate) fn shell_quote(path: &Path) -> Vec<u8> {
+    let bytes = path.as_os_str().as_bytes();
+    let mut out = Vec::with_capacity(bytes.len() + 2);
+    out.push(b'\'');
+    for &b in bytes {
+        if b == b'\'' {
+            out.extend_from_slice(b"'\\''");
+        } else {
+            out.push(b);
+        }
+    }
+    out.push(b'\'');
+    out
+}
+
+impl ScanListener for ShellScriptOutput {
+    fn file_scanned(&mut self, _: &Path, _: &Stats) {}
+
+    fn scan_over(&self, _: &Scanner, stats: &Stats, _: Duration) {
+        if self.header_written {
+            println!();
+        } else {
+            println!("#!/bin/sh");
+        }
+        println!("# {} dupes", stats.dupes);
+    }
+
+    fn hardlinked(&mut self, src: &Path, dst: &Path) {
+        // Not expected in dry run, but if it happens, the script should still reflect it
+        self.duplicate_found(src, dst);
+    }
+
+    fn duplicate_found(&mut self, src: &Path, dst: &Path) {
+        if let Err(err) = self.write_link(dst, src) {
+            eprintln!("Can't write script: {}", err);
+        }
+    }
+}
+
+#[test]
+fn quote_test() {
+    assert_eq!(shell_quote(Path::new("/a b/c")), b"'/a b/c'");
+    assert_eq!(shell_quote(Path::new("it's")), b"'it'\\''s'");
+    assert_eq!(shell_quote(Path::new("$HOME`x`")), b"'$HOME`x`'");
+}
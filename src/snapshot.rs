@@ -0,0 +1,60 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::path::Path;
+
+/// Directories with read-only copies of the filesystem. Their files can't be linked (`EROFS`),
+/// and scanning them would find a dupe of everything that has been snapshotted.
+const SNAPSHOT_DIR_NAMES: &[&str] = &[
+    ".snapshot",
+    // snapper
+    ".snapshots",
+    // `.zfs/snapshot`. The rest of `.zfs` is virtual too.
+    ".zfs",
+    // Time Machine
+    ".MobileBackups",
+    ".timemachine",
+    "Backups.backupdb",
+    "com.apple.TimeMachine.localsnapshots",
+];
+
+/// `ino` is the directory's inode number, which is free to get from `DirEntry`
+pub(crate) fn is_snapshot_dir(path: &Path, ino: u64) -> bool {
+    let name = path.file_name().unwrap_or_default();
+    SNAPSHOT_DIR_NAMES.iter().any(|&n| OsStr::new(n) == name) || is_btrfs_readonly_subvolume(path, ino)
+}
+
+#[cfg(target_os = "linux")]
+fn is_btrfs_readonly_subvolume(path: &Path, ino: u64) -> bool {
+    use std::os::unix::io::AsRawFd;
+
+    // Btrfs subvolumes always have this inode number, so other directories don't need to be opened
+    const BTRFS_FIRST_FREE_OBJECTID: u64 = 256;
+    // _IOR(BTRFS_IOCTL_MAGIC, 25, u64)
+    const BTRFS_IOC_SUBVOL_GETFLAGS: libc::c_ulong = 0x8008_9419;
+    const BTRFS_SUBVOL_RDONLY: u64 = 1 << 1;
+
+    if ino != BTRFS_FIRST_FREE_OBJECTID {
+        return false;
+    }
+    let dir = match fs::File::open(path) {
+        Ok(dir) => dir,
+        Err(_) => return false,
+    };
+    let mut flags = 0u64;
+    // Fails on other filesystems
+    let res = unsafe { libc::ioctl(dir.as_raw_fd(), BTRFS_IOC_SUBVOL_GETFLAGS as _, &mut flags) };
+    res == 0 && flags & BTRFS_SUBVOL_RDONLY != 0
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_btrfs_readonly_subvolume(_: &Path, _: u64) -> bool {
+    false
+}
+
+#[test]
+fn snapshot_dir_test() {
+    assert!(is_snapshot_dir(Path::new("/tank/.zfs"), 1));
+    assert!(is_snapshot_dir(Path::new("home/.snapshots"), 1));
+    assert!(!is_snapshot_dir(Path::new("/home/snapshot"), 1));
+    assert!(!is_snapshot_dir(Path::new("/"), 256));
+}
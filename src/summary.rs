@@ -0,0 +1,113 @@
+use crate::json::{JsonLink, JsonPath, JsonReport};
+use crate::ui::{human_size, nice_duration};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Numbers from a report saved with `--json`, so that they can be looked at without scanning again
+#[derive(Debug, Clone)]
+pub struct ReportSummary {
+    pub groups: usize,
+    /// Paths in all groups, counting every hardlink
+    pub files: usize,
+    /// Groups that have been hardlinked (the rest were found in a dry run)
+    pub linked_groups: usize,
+    /// Saved by linking, or that would be saved in a dry run
+    pub bytes_saved: u64,
+    /// Groups that save the most, largest first
+    pub top_groups: Vec<TopGroup>,
+    /// Bytes saved by lowercase file extension, largest first. Files without an extension are under `""`.
+    pub by_extension: Vec<(String, u64)>,
+    pub files_scanned: usize,
+    pub files_skipped: usize,
+    pub scan_duration: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct TopGroup {
+    pub bytes_saved: u64,
+    /// Size of each file
+    pub size: u64,
+    pub copies: usize,
+    /// One of the files
+    pub path: JsonPath,
+}
+
+impl ReportSummary {
+    /// Keeps `top` largest groups. Reports from versions before groups had sizes don't have savings.
+    pub fn new(report: &JsonReport, top: usize) -> Self {
+        let mut summary = ReportSummary {
+            groups: report.dupes.len(),
+            files: report.dupes.iter().flatten().map(|set| set.len()).sum(),
+            linked_groups: report.groups.iter().filter(|g| g.link == JsonLink::Hardlink).count(),
+            bytes_saved: report.groups.iter().map(|g| g.bytes_saved).sum(),
+            top_groups: Vec::new(),
+            by_extension: Vec::new(),
+            files_scanned: report.stats.added,
+            files_skipped: report.stats.skipped,
+            scan_duration: report.scan_duration,
+        };
+
+        let mut by_extension = HashMap::new();
+        let mut top_groups: Vec<_> = report.dupes.iter().zip(&report.groups).filter_map(|(sets, group)| {
+            let path = sets.iter().flatten().next()?;
+            let extension = path.extension().map(|ext| ext.to_string_lossy().to_lowercase()).unwrap_or_default();
+            *by_extension.entry(extension).or_insert(0) += group.bytes_saved;
+            Some(TopGroup { bytes_saved: group.bytes_saved, size: group.size, copies: group.copies, path: path.clone() })
+        }).collect();
+        top_groups.sort_by(|a, b| b.bytes_saved.cmp(&a.bytes_saved).then_with(|| a.path.cmp(&b.path)));
+        top_groups.truncate(top);
+        summary.top_groups = top_groups;
+
+        let mut by_extension: Vec<_> = by_extension.into_iter().filter(|&(_, size)| size > 0).collect();
+        by_extension.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        summary.by_extension = by_extension;
+        summary
+    }
+
+    /// Prints the summary to stdout, with sizes in KiB, MiB, etc., unless `exact_bytes` is set
+    pub fn print(&self, exact_bytes: bool) {
+        let size = |size: u64| if exact_bytes { format!("{}B", size) } else { human_size(size as usize) };
+        println!("{} groups of duplicates, {} files. Scanned {}+{} files in {}.", self.groups, self.files, self.files_scanned, self.files_skipped, nice_duration(self.scan_duration));
+        if self.linked_groups > 0 {
+            println!("Saved {} ({} groups hardlinked).", size(self.bytes_saved), self.linked_groups);
+        } else {
+            println!("Hardlinking would save {}.", size(self.bytes_saved));
+        }
+        if !self.top_groups.is_empty() {
+            println!("Largest groups:");
+            for group in &self.top_groups {
+                println!("{:>12}  {} copies of {}  {}", size(group.bytes_saved), group.copies, size(group.size), group.path.display());
+            }
+        }
+        if !self.by_extension.is_empty() {
+            println!("By file extension:");
+            for (ext, bytes) in self.by_extension.iter().take(10) {
+                println!("{:>12}  {}", size(*bytes), if ext.is_empty() { "(none)".to_string() } else { format!(".{}", ext) });
+            }
+        }
+    }
+}
+
+#[test]
+fn summary_test() {
+    use crate::scanner::{RunMode, Scanner, Stats};
+    use std::fs;
+
+    let dir = tempdir::TempDir::new("summarytest").unwrap();
+    for name in ["a.JPG", "b.jpg", "c.jpg"] {
+        fs::write(dir.path().join(name), "photo").unwrap();
+    }
+    for name in ["d", "e"] {
+        fs::write(dir.path().join(name), "longer file!").unwrap();
+    }
+    let mut s = Scanner::new();
+    s.settings.ignore_small = false;
+    s.settings.run_mode = RunMode::DryRun;
+    s.scan(dir.path()).unwrap();
+    let summary = ReportSummary::new(&JsonReport::new(&s, &Stats::default(), Duration::ZERO), 1);
+    assert_eq!((2, 5, 0), (summary.groups, summary.files, summary.linked_groups));
+    assert_eq!(22, summary.bytes_saved);
+    assert_eq!(1, summary.top_groups.len());
+    assert_eq!((12, 2), (summary.top_groups[0].bytes_saved, summary.top_groups[0].copies));
+    assert_eq!(vec![(String::new(), 12), ("jpg".to_string(), 10)], summary.by_extension);
+}
@@ -0,0 +1,105 @@
+use crate::scanner::ScanListener;
+use crate::scanner::Scanner;
+use crate::scanner::Stats;
+use std::env;
+use std::ffi::OsString;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Tells systemd when the service is ready, and shows progress in `systemctl status` (for services with `Type=notify`).
+#[derive(Debug)]
+pub struct SystemdNotify {
+    socket: OsString,
+    last_status: Instant,
+}
+
+impl SystemdNotify {
+    /// `None` if it's not running as a systemd service (`NOTIFY_SOCKET` isn't set)
+    pub fn from_env() -> Option<Self> {
+        let socket = env::var_os("NOTIFY_SOCKET")?;
+        Some(SystemdNotify { socket, last_status: Instant::now() })
+    }
+
+    /// To be sent once, when the program has started
+    pub fn ready(&self) {
+        self.send("READY=1");
+    }
+
+    fn send(&self, message: &str) {
+        if let Err(err) = send(&self.socket, message) {
+            eprintln!("Can't notify systemd: {}", err);
+        }
+    }
+}
+
+impl ScanListener for SystemdNotify {
+    fn file_scanned(&mut self, _: &Path, stats: &Stats) {
+        if self.last_status.elapsed() < Duration::from_secs(1) {
+            return;
+        }
+        self.last_status = Instant::now();
+        self.send(&format!("STATUS={} files scanned, {} dupes found", stats.added, stats.dupes));
+    }
+
+    fn scan_over(&self, _: &Scanner, stats: &Stats, _: Duration) {
+        self.send(&format!("STOPPING=1\nSTATUS=Done. {} dupes, {} bytes deduplicated", stats.dupes, stats.bytes_deduplicated));
+    }
+
+    fn hardlinked(&mut self, _: &Path, _: &Path) {}
+
+    fn duplicate_found(&mut self, _: &Path, _: &Path) {}
+}
+
+/// Datagram to the socket, which can be a path, or an abstract socket name starting with `@`
+#[cfg(target_os = "linux")]
+fn send(socket: &std::ffi::OsStr, message: &str) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    let name = socket.as_bytes();
+    if name.is_empty() || name.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid NOTIFY_SOCKET"));
+    }
+    for (dst, &src) in addr.sun_path.iter_mut().zip(name) {
+        *dst = src as libc::c_char;
+    }
+    if name[0] == b'@' {
+        addr.sun_path[0] = 0;
+    }
+    let len = std::mem::size_of::<libc::sa_family_t>() + name.len();
+    let sent = unsafe {
+        libc::sendto(fd.as_raw_fd(), message.as_ptr().cast(), message.len(), libc::MSG_NOSIGNAL,
+            (&addr as *const libc::sockaddr_un).cast(), len as libc::socklen_t)
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send(_: &std::ffi::OsStr, _: &str) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn notify_test() {
+    use std::os::unix::net::UnixDatagram;
+
+    let dir = tempdir::TempDir::new("notifytest").unwrap();
+    let path = dir.path().join("notify");
+    let receiver = UnixDatagram::bind(&path).unwrap();
+    send(path.as_os_str(), "READY=1").unwrap();
+    let mut buf = [0; 16];
+    let len = receiver.recv(&mut buf).unwrap();
+    assert_eq!(b"READY=1", &buf[..len]);
+}
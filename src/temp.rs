@@ -0,0 +1,80 @@
+use std::collections::hash_map::RandomState;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// All temporary files start with it, so that they can be recognized and cleaned up after a crash
+const TEMP_PREFIX: &str = ".tmp-dupe-krill-";
+
+/// Unique name for a temporary file in the same directory as `next_to`.
+/// The name has the host name and process id, so that it's possible to tell whether it's still in use.
+pub(crate) fn temp_path(next_to: &Path) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    // RandomState is seeded randomly for every process
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_usize(COUNTER.fetch_add(1, Ordering::Relaxed));
+    let mut name = TEMP_PREFIX.as_bytes().to_vec();
+    name.extend(hostname());
+    name.extend(format!("-{}-{:016x}", std::process::id(), hasher.finish()).bytes());
+    next_to.with_file_name(OsString::from_vec(name))
+}
+
+/// `Some` if the file name is of a temporary file. The bool is true if it's been made on this machine by a process that isn't running any more.
+/// Processes of other machines (e.g. sharing the directory over NFS) can't be checked, so their files are never stale.
+pub(crate) fn temp_file_status(file_name: &OsStr) -> Option<bool> {
+    let rest = file_name.as_bytes().strip_prefix(TEMP_PREFIX.as_bytes())?;
+    // The host name may contain dashes too
+    let mut parts = rest.rsplitn(3, |&c| c == b'-');
+    let _random = parts.next()?;
+    let pid: libc::pid_t = std::str::from_utf8(parts.next()?).ok()?.parse().ok()?;
+    if parts.next() != Some(&hostname()[..]) {
+        return Some(false);
+    }
+    Some(!is_running(pid))
+}
+
+/// Empty if unknown. Can't contain a `/`, because it's used in file names.
+fn hostname() -> Vec<u8> {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return Vec::new();
+    }
+    buf.iter().take_while(|&&c| c != 0).map(|&c| if c == b'/' { b'_' } else { c }).collect()
+}
+
+/// Makes a rename or link in the directory of `path` durable
+pub(crate) fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    let dir = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    fs::File::open(dir)?.sync_all()
+}
+
+fn is_running(pid: libc::pid_t) -> bool {
+    if pid == std::process::id() as libc::pid_t {
+        return true;
+    }
+    // Signal 0 only checks if the process exists. EPERM means it exists, but belongs to someone else.
+    let res = unsafe { libc::kill(pid, 0) };
+    res == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[test]
+fn temp_path_test() {
+    let a = temp_path(Path::new("/foo/bar"));
+    let b = temp_path(Path::new("/foo/bar"));
+    assert_ne!(a, b);
+    assert_eq!(Path::new("/foo"), a.parent().unwrap());
+    assert_eq!(Some(false), temp_file_status(a.file_name().unwrap()));
+    assert_eq!(None, temp_file_status(OsStr::new("bar")));
+    assert_eq!(None, temp_file_status(OsStr::new(".tmp-dupe-krill-x")));
+
+    // Files of processes that have exited are stale only if they're from this machine
+    let mut name = TEMP_PREFIX.as_bytes().to_vec();
+    name.extend(hostname());
+    name.extend(b"-2147483647-0123456789abcdef");
+    assert_eq!(Some(true), temp_file_status(&OsString::from_vec(name)));
+    assert_eq!(Some(false), temp_file_status(OsStr::new(".tmp-dupe-krill-other-host-2147483647-0123456789abcdef")));
+}
@@ -0,0 +1,141 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+/// Moves the file to the freedesktop.org trash, so that it can be restored from a file manager. Returns its new path.
+///
+/// Files on other filesystems than the home directory go to `.Trash-<uid>` at the top of their filesystem, as the spec says.
+pub(crate) fn move_to_trash(path: &Path) -> io::Result<PathBuf> {
+    let path = if path.is_absolute() { path.to_path_buf() } else { std::env::current_dir()?.join(path) };
+    let dev = fs::symlink_metadata(&path)?.dev();
+    let (trash, original_path) = match home_trash() {
+        Some(home_trash) if device_of(&home_trash) == Some(dev) => (home_trash, path.clone()),
+        _ => {
+            let top = mount_point(&path, dev);
+            let uid = unsafe { libc::getuid() };
+            // Paths in a trash at the top of a filesystem are relative to it
+            let relative = path.strip_prefix(&top).unwrap_or(&path).to_path_buf();
+            (top.join(format!(".Trash-{}", uid)), relative)
+        },
+    };
+    let files_dir = trash.join("files");
+    let info_dir = trash.join("info");
+    // Only readable by the owner, as the spec requires
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(&files_dir)?;
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(&info_dir)?;
+
+    // The info file is created exclusively first, to reserve a unique name in the trash
+    let file_name = path.file_name().unwrap_or_default();
+    let (name, mut info, info_path) = (0u32..).find_map(|n| {
+        let mut name = file_name.to_owned();
+        if n > 0 {
+            name.push(format!(".{}", n));
+        }
+        let mut info_name = name.clone();
+        info_name.push(".trashinfo");
+        let info_path = info_dir.join(info_name);
+        match fs::OpenOptions::new().write(true).create_new(true).open(&info_path) {
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => None,
+            res => Some(res.map(|info| (name, info, info_path))),
+        }
+    }).expect("endless")?;
+    let res = write!(info, "[Trash Info]\nPath={}\nDeletionDate={}\n", percent_encode(original_path.as_os_str()), local_time_now())
+        .and_then(|_| move_file(&path, &files_dir.join(name)));
+    match res {
+        Ok(new_path) => Ok(new_path),
+        Err(err) => {
+            let _ = fs::remove_file(info_path);
+            Err(err)
+        },
+    }
+}
+
+/// Moves the file to the same path relative to `dir`. Never overwrites existing files.
+pub(crate) fn move_to_dir(path: &Path, relative_path: &Path, dir: &Path) -> io::Result<PathBuf> {
+    // Files outside of scanned directories may have absolute paths, which would replace `dir` in `join`
+    let new_path = dir.join(relative_path.strip_prefix("/").unwrap_or(relative_path));
+    if let Some(parent) = new_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    move_file(path, &new_path)
+}
+
+/// Directories that trashed files are moved to, which shouldn't be scanned in the trash mode
+pub(crate) fn is_trash_dir(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().as_bytes();
+    name == b".Trash" || name.starts_with(b".Trash-") || home_trash().map_or(false, |home_trash| home_trash == path)
+}
+
+/// Unlike rename, link doesn't overwrite the destination. Works only within one filesystem.
+fn move_file(path: &Path, new_path: &Path) -> io::Result<PathBuf> {
+    fs::hard_link(path, new_path)?;
+    if let Err(err) = fs::remove_file(path) {
+        let _ = fs::remove_file(new_path);
+        return Err(err);
+    }
+    Ok(new_path.to_path_buf())
+}
+
+fn home_trash() -> Option<PathBuf> {
+    let data_home = std::env::var_os("XDG_DATA_HOME").filter(|dir| !dir.is_empty()).map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))?;
+    Some(data_home.join("Trash"))
+}
+
+/// Device of the path, or of its closest parent that exists
+fn device_of(path: &Path) -> Option<u64> {
+    path.ancestors().find_map(|dir| fs::metadata(dir).ok()).map(|m| m.dev())
+}
+
+fn mount_point(path: &Path, dev: u64) -> PathBuf {
+    let mut top = path;
+    while let Some(parent) = top.parent() {
+        if fs::metadata(parent).map_or(true, |m| m.dev() != dev) {
+            break;
+        }
+        top = parent;
+    }
+    top.to_path_buf()
+}
+
+fn percent_encode(path: &OsStr) -> String {
+    let mut out = String::with_capacity(path.len());
+    for &c in path.as_bytes() {
+        if c.is_ascii_alphanumeric() || b"/-._~".contains(&c) {
+            out.push(c as char);
+        } else {
+            out.push_str(&format!("%{:02X}", c));
+        }
+    }
+    out
+}
+
+/// The spec wants local time, without a time zone
+fn local_time_now() -> String {
+    let now = unsafe { libc::time(std::ptr::null_mut()) };
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&now, &mut tm) };
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}", tm.tm_year + 1900, tm.tm_mon + 1, tm.tm_mday, tm.tm_hour, tm.tm_min, tm.tm_sec)
+}
+
+#[test]
+fn trash_test() {
+    assert_eq!("/a%20b/%C5%BC.txt", percent_encode(OsStr::new("/a b/ż.txt")));
+
+    let dir = tempdir::TempDir::new("trashtest").unwrap();
+    let file = dir.path().join("file");
+    fs::write(&file, "x").unwrap();
+    let moved = move_to_dir(&file, Path::new("sub/file"), &dir.path().join("quarantine")).unwrap();
+    assert_eq!(dir.path().join("quarantine/sub/file"), moved);
+    assert!(!file.exists());
+
+    // Doesn't overwrite
+    fs::write(&file, "y").unwrap();
+    assert!(move_to_dir(&file, Path::new("sub/file"), &dir.path().join("quarantine")).is_err());
+    assert!(file.exists());
+    assert_eq!("x", fs::read_to_string(moved).unwrap());
+}
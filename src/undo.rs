@@ -0,0 +1,279 @@
+use crate::temp::{sync_parent_dir, temp_path};
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+const HEADER: &str = "# dupe-krill undo log v1: mode uid gid mtime mtime_nsec kept_path replaced_path";
+
+/// Journal of replaced files, written before each file is replaced,
+/// so that the hardlinks can be broken up again later with `undo()`.
+#[derive(Debug)]
+pub struct UndoLog {
+    file: fs::File,
+    /// Working directory when the log has been created, since the undo may run elsewhere
+    cwd: PathBuf,
+}
+
+impl UndoLog {
+    /// Appends to the log if it already exists
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = fs::OpenOptions::new().append(true).create(true).open(path)?;
+        if file.metadata()?.len() == 0 {
+            writeln!(file, "{}", HEADER)?;
+        }
+        Ok(UndoLog { file, cwd: std::env::current_dir()? })
+    }
+
+    /// `replaced_metadata` is of the file before it's replaced
+    pub fn record(&mut self, kept: &Path, replaced: &Path, replaced_metadata: &fs::Metadata) -> io::Result<()> {
+        let record = UndoRecord {
+            mode: replaced_metadata.mode(),
+            uid: replaced_metadata.uid(),
+            gid: replaced_metadata.gid(),
+            mtime: replaced_metadata.mtime(),
+            mtime_nsec: replaced_metadata.mtime_nsec(),
+            kept: absolute(&self.cwd, kept),
+            replaced: absolute(&self.cwd, replaced),
+        };
+        // Written in one go, and without buffering, so that the log is up to date even if the process is killed
+        self.file.write_all(record.to_line().as_bytes())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct UndoRecord {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: i64,
+    mtime_nsec: i64,
+    kept: PathBuf,
+    replaced: PathBuf,
+}
+
+impl UndoRecord {
+    fn to_line(&self) -> String {
+        format!("{:o}\t{}\t{}\t{}\t{}\t{}\t{}\n", self.mode, self.uid, self.gid, self.mtime, self.mtime_nsec, escape(&self.kept), escape(&self.replaced))
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        let mut fields = line.split('\t');
+        let record = UndoRecord {
+            mode: u32::from_str_radix(fields.next()?, 8).ok()?,
+            uid: fields.next()?.parse().ok()?,
+            gid: fields.next()?.parse().ok()?,
+            mtime: fields.next()?.parse().ok()?,
+            mtime_nsec: fields.next()?.parse().ok()?,
+            kept: unescape(fields.next()?)?,
+            replaced: unescape(fields.next()?)?,
+        };
+        if fields.next().is_some() {
+            return None;
+        }
+        Some(record)
+    }
+
+    /// Gives the replaced path its own copy of the file, with its original permissions and mtime
+    fn restore(&self) -> io::Result<bool> {
+        let kept = fs::symlink_metadata(&self.kept)?;
+        let replaced = fs::symlink_metadata(&self.replaced)?;
+        if kept.dev() != replaced.dev() || kept.ino() != replaced.ino() {
+            // Has been changed since, or the link has never been made
+            return Ok(false);
+        }
+
+        let temp_path = temp_path(&self.replaced);
+        let res = self.copy_to(&temp_path).and_then(|_| fs::rename(&temp_path, &self.replaced));
+        if res.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        res?;
+        sync_parent_dir(&self.replaced)?;
+        Ok(true)
+    }
+
+    /// The copy is complete and on disk before it gets the temporary name.
+    /// On Linux it's written to an anonymous file, so that a crash can't leave a partial copy behind.
+    fn copy_to(&self, temp_path: &Path) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        if let Some(dst) = open_anonymous(temp_path)? {
+            self.write_copy(&dst)?;
+            // Without /proc the copy is lost, but can be made again the usual way
+            if link_anonymous(&dst, temp_path).is_ok() {
+                return Ok(());
+            }
+        }
+        let dst = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(temp_path)?;
+        self.write_copy(&dst)
+    }
+
+    fn write_copy(&self, mut dst: &fs::File) -> io::Result<()> {
+        let mut src = fs::File::open(&self.kept)?;
+        io::copy(&mut src, &mut dst)?;
+
+        // Only root can give files away, so this is expected to fail for other users' files
+        if unsafe { libc::fchown(dst.as_raw_fd(), self.uid, self.gid) } != 0 {
+            let err = io::Error::last_os_error();
+            eprintln!("Can't restore owner of {}: {}", self.replaced.display(), err);
+        }
+        // After chown, since it may clear setuid bits
+        dst.set_permissions(fs::Permissions::from_mode(self.mode & 0o7777))?;
+
+        let times = [
+            libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+            libc::timespec { tv_sec: self.mtime as libc::time_t, tv_nsec: self.mtime_nsec as _ },
+        ];
+        if unsafe { libc::futimens(dst.as_raw_fd(), times.as_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Otherwise a crash after the rename could leave an empty file in place of the data
+        dst.sync_all()
+    }
+}
+
+/// `O_TMPFILE` file in the same directory as `path`. `None` if the filesystem doesn't support it.
+#[cfg(target_os = "linux")]
+fn open_anonymous(path: &Path) -> io::Result<Option<fs::File>> {
+    let dir = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    match fs::OpenOptions::new().write(true).custom_flags(libc::O_TMPFILE).mode(0o600).open(dir) {
+        Ok(file) => Ok(Some(file)),
+        Err(err) if matches!(err.raw_os_error(), Some(libc::EOPNOTSUPP | libc::EISDIR | libc::EINVAL)) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Gives a name to the `O_TMPFILE` file
+#[cfg(target_os = "linux")]
+fn link_anonymous(file: &fs::File, path: &Path) -> io::Result<()> {
+    let proc_path = std::ffi::CString::new(format!("/proc/self/fd/{}", file.as_raw_fd()))?;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::linkat(libc::AT_FDCWD, proc_path.as_ptr(), libc::AT_FDCWD, path.as_ptr(), libc::AT_SYMLINK_FOLLOW) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// `path` relative to `cwd`. Paths written to files that are read later (undo logs, checkpoints, reports) have to be absolute,
+/// since they may be used from another directory.
+pub(crate) fn absolute(cwd: &Path, path: &Path) -> PathBuf {
+    let path = path.strip_prefix(".").unwrap_or(path);
+    if path.as_os_str().is_empty() {
+        cwd.to_path_buf()
+    } else {
+        cwd.join(path)
+    }
+}
+
+/// Tabs and newlines are escaped, because they separate fields and records.
+/// Non-UTF-8 paths are escaped byte by byte.
+pub(crate) fn escape(path: &Path) -> String {
+    let bytes = path.as_os_str().as_bytes();
+    let mut out = String::with_capacity(bytes.len());
+    match std::str::from_utf8(bytes) {
+        Ok(s) => {
+            for ch in s.chars() {
+                match ch {
+                    '\\' => out.push_str("\\\\"),
+                    '\t' => out.push_str("\\t"),
+                    '\n' => out.push_str("\\n"),
+                    '\r' => out.push_str("\\r"),
+                    ch => out.push(ch),
+                }
+            }
+        },
+        Err(_) => {
+            for &b in bytes {
+                match b {
+                    b'\\' | b'\t' | b'\n' | b'\r' | 0x80..=0xFF => out.push_str(&format!("\\x{:02x}", b)),
+                    b => out.push(b as char),
+                }
+            }
+        },
+    }
+    out
+}
+
+pub(crate) fn unescape(field: &str) -> Option<PathBuf> {
+    let mut out = Vec::with_capacity(field.len());
+    let mut bytes = field.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        out.push(match bytes.next()? {
+            b'\\' => b'\\',
+            b't' => b'\t',
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b'x' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            },
+            _ => return None,
+        });
+    }
+    Some(OsString::from_vec(out).into())
+}
+
+/// Outcome of `undo()`
+#[derive(Debug, Default, Copy, Clone)]
+pub struct UndoStats {
+    /// Files that got their own copy back
+    pub restored: usize,
+    /// Files that weren't hardlinked anymore
+    pub unchanged: usize,
+    pub errors: usize,
+}
+
+/// Breaks up hardlinks recorded in the undo log, so that every replaced file becomes an independent copy again.
+/// Records are processed newest first.
+pub fn undo(log_path: impl AsRef<Path>) -> io::Result<UndoStats> {
+    let log = fs::read_to_string(log_path)?;
+    let mut stats = UndoStats::default();
+    for line in log.lines().rev() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let record = UndoRecord::from_line(line).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid undo log line: {}", line)))?;
+        match record.restore() {
+            Ok(true) => stats.restored += 1,
+            Ok(false) => stats.unchanged += 1,
+            Err(err) => {
+                eprintln!("Can't restore {}: {}", record.replaced.display(), err);
+                stats.errors += 1;
+            },
+        }
+    }
+    Ok(stats)
+}
+
+#[test]
+fn absolute_test() {
+    let cwd = Path::new("/home/user");
+    assert_eq!(Path::new("/home/user/a/b"), absolute(cwd, Path::new("a/b")));
+    assert_eq!(Path::new("/home/user/a"), absolute(cwd, Path::new("./a")));
+    assert_eq!(Path::new("/home/user"), absolute(cwd, Path::new(".")));
+    assert_eq!(Path::new("/tmp/a"), absolute(cwd, Path::new("/tmp/a")));
+}
+
+#[test]
+fn undo_record_roundtrip() {
+    let record = UndoRecord {
+        mode: 0o100644,
+        uid: 501,
+        gid: 20,
+        mtime: 1_600_000_000,
+        mtime_nsec: 123,
+        kept: "/tmp/a\tb\\c/żółw".into(),
+        replaced: PathBuf::from(OsString::from_vec(b"/tmp/\xff\n".to_vec())),
+    };
+    let line = record.to_line();
+    assert_eq!(1, line.lines().count());
+    assert_eq!(Some(record), UndoRecord::from_line(line.trim_end_matches('\n')));
+}
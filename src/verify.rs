@@ -0,0 +1,94 @@
+use crate::file::FileContent;
+use crate::json::{JsonGroup, JsonLink, JsonPath, JsonReport};
+use crate::metadata::Metadata;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// Outcome of `verify()`
+#[derive(Debug, Default, Copy, Clone)]
+pub struct VerifyStats {
+    /// Groups where all files still exist, are identical, and are still hardlinked if they were
+    pub intact: usize,
+    pub diverged: usize,
+}
+
+/// Checks that files in every group of the report are still identical, and that hardlinks haven't been broken.
+/// Problems are printed to stdout, one per line.
+pub fn verify(report: &JsonReport) -> VerifyStats {
+    let mut stats = VerifyStats::default();
+    for (i, sets) in report.dupes.iter().enumerate() {
+        // Reports from older versions don't have the groups
+        let problems = verify_group(sets, report.groups.get(i));
+        if problems.is_empty() {
+            stats.intact += 1;
+        } else {
+            stats.diverged += 1;
+            for problem in problems {
+                println!("{}", problem);
+            }
+        }
+    }
+    stats
+}
+
+fn verify_group(sets: &[Vec<JsonPath>], group: Option<&JsonGroup>) -> Vec<String> {
+    let all_linked = group.map_or(false, |g| g.link == JsonLink::Hardlink);
+    let mut problems = Vec::new();
+    let mut first: Option<(&Path, (u64, u64), FileContent)> = None;
+    for set in sets {
+        let mut set_first: Option<(&Path, (u64, u64))> = None;
+        for path in set {
+            let m = match fs::symlink_metadata(path) {
+                Ok(m) => m,
+                Err(err) => {
+                    problems.push(format!("{}: {}", path.display(), err));
+                    continue;
+                },
+            };
+            if let Some(size) = group.map(|g| g.size).filter(|&size| size != m.len()) {
+                problems.push(format!("{}: size changed from {} to {}", path.display(), size, m.len()));
+                continue;
+            }
+            let inode = (m.dev(), m.ino());
+            let expected_link = if all_linked { first.as_ref().map(|f| (f.0, f.1)) } else { set_first };
+            if let Some((linked_path, linked_inode)) = expected_link {
+                if inode != linked_inode {
+                    problems.push(format!("{}: not a hardlink of {} anymore", path.display(), linked_path.display()));
+                }
+            }
+            set_first.get_or_insert((path, inode));
+            match &first {
+                None => first = Some((path, inode, FileContent::new(path.0.clone(), Metadata::new(&m)))),
+                // Hardlinks of the same file are obviously identical
+                Some((first_path, first_inode, content)) => if inode != *first_inode && FileContent::new(path.0.clone(), Metadata::new(&m)) != *content {
+                    problems.push(format!("{}: content differs from {}", path.display(), first_path.display()));
+                },
+            }
+        }
+    }
+    problems
+}
+
+#[test]
+fn verify_test() {
+    use crate::scanner::{RunMode, Scanner, Stats};
+    use std::time::Duration;
+
+    let dir = tempdir::TempDir::new("verifytest").unwrap();
+    let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+    fs::write(&a, "same").unwrap();
+    fs::write(&b, "same").unwrap();
+
+    let mut s = Scanner::new();
+    s.settings.ignore_small = false;
+    s.settings.run_mode = RunMode::Hardlink;
+    s.scan(dir.path()).unwrap();
+    let report = JsonReport::new(&s, &Stats::default(), Duration::ZERO);
+    assert_eq!(1, verify(&report).intact);
+
+    fs::remove_file(&b).unwrap();
+    fs::write(&b, "diff").unwrap();
+    let stats = verify(&report);
+    assert_eq!((0, 1), (stats.intact, stats.diverged));
+}
@@ -1,6 +1,7 @@
 
 use dupe_krill::*;
 use std::fs;
+use std::time::Duration;
 
 use tempdir::TempDir;
 
@@ -55,3 +56,687 @@ fn scan_hardlink() {
     assert_eq!(dupes.len(), 1);
     assert_eq!(dupes[0][0].paths.len(), 2);
 }
+
+#[test]
+fn age_filter() {
+    let dir = TempDir::new("agetest").unwrap();
+    fs::write(dir.path().join("a"), "foo").unwrap();
+    fs::write(dir.path().join("b"), "foo").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.run_mode = RunMode::DryRunNoMerging;
+    d.settings.older_than = Some(Duration::from_secs(3600));
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.dupes().len(), 0);
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.run_mode = RunMode::DryRunNoMerging;
+    d.settings.newer_than = Some(Duration::from_secs(3600));
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.dupes().len(), 1);
+    assert_eq!(d.dupes()[0].len(), 2);
+}
+
+#[test]
+fn age_checked_before_replacing() {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = TempDir::new("agerechecktest").unwrap();
+    let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+    fs::write(&a, "foo").unwrap();
+    fs::write(&b, "foo").unwrap();
+    // Existing hardlinks make it wait for flush()
+    fs::hard_link(&a, dir.path().join("a2")).unwrap();
+    let times = [libc::timeval { tv_sec: 1_000_000_000, tv_usec: 0 }; 2];
+    for path in [&a, &b] {
+        let path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+        assert_eq!(0, unsafe { libc::utimes(path.as_ptr(), times.as_ptr()) });
+    }
+
+    let b_ino = fs::metadata(&b).unwrap().ino();
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.older_than = Some(Duration::from_secs(3600));
+    d.add_files(vec![a.clone(), dir.path().join("a2"), b.clone()]);
+    // Modified after it has been scanned
+    fs::write(&b, "foo").unwrap();
+    d.flush().unwrap();
+    assert_eq!(b_ino, fs::metadata(&b).unwrap().ino());
+}
+
+#[test]
+fn paths_as_given() {
+    let dir = TempDir::new("symlinkroottest").unwrap();
+    let real = dir.path().join("real");
+    let link = dir.path().join("link");
+    fs::create_dir(&real).unwrap();
+    fs::write(real.join("a"), "foo").unwrap();
+    fs::write(real.join("b"), "foo").unwrap();
+    std::os::unix::fs::symlink(&real, &link).unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.run_mode = RunMode::DryRun;
+    d.scan(&link).unwrap();
+    let dupes = d.dupes();
+    assert_eq!(dupes.len(), 1);
+    assert!(dupes[0].iter().flat_map(|set| set.paths.iter()).all(|p| p.starts_with(&link)));
+}
+
+#[test]
+fn duplicate_dirs() {
+    let dir = TempDir::new("dirtest").unwrap();
+    for name in ["a", "a/sub", "b", "b/sub", "c"] {
+        fs::create_dir(dir.path().join(name)).unwrap();
+    }
+    for name in ["a", "b", "c"] {
+        fs::write(dir.path().join(name).join("x"), "foo").unwrap();
+        fs::write(dir.path().join(name).join("y"), "bar").unwrap();
+    }
+    fs::write(dir.path().join("a/sub/z"), "baz").unwrap();
+    fs::write(dir.path().join("b/sub/z"), "baz").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.run_mode = RunMode::DryRunNoMerging;
+    d.scan(dir.path()).unwrap();
+    let dirs = d.duplicate_dirs();
+    assert_eq!(dirs, vec![vec![dir.path().join("a"), dir.path().join("b")]]);
+}
+
+#[test]
+fn similar_dirs() {
+    let dir = TempDir::new("similartest").unwrap();
+    for name in ["a", "b"] {
+        fs::create_dir(dir.path().join(name)).unwrap();
+        fs::write(dir.path().join(name).join("x"), "foo").unwrap();
+    }
+    fs::write(dir.path().join("a/y"), "bar").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.run_mode = RunMode::DryRunNoMerging;
+    d.scan(dir.path()).unwrap();
+    let similar = d.similar_dirs();
+    assert_eq!(1, similar.len());
+    assert_eq!((dir.path().join("a"), dir.path().join("b")), similar[0].dirs);
+    assert_eq!(3, similar[0].common_bytes);
+    assert_eq!(0.5, similar[0].similarity);
+}
+
+#[test]
+fn hardlink_groups() {
+    let dir = TempDir::new("linkgroupstest").unwrap();
+    fs::write(dir.path().join("a"), "foo").unwrap();
+    fs::hard_link(dir.path().join("a"), dir.path().join("b")).unwrap();
+    fs::write(dir.path().join("c"), "bar").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.run_mode = RunMode::DryRunNoMerging;
+    d.scan(dir.path()).unwrap();
+    let groups = d.hardlink_groups();
+    assert_eq!(1, groups.len());
+    let mut paths: Vec<_> = groups[0].paths.iter().cloned().collect();
+    paths.sort();
+    assert_eq!(paths, vec![dir.path().join("a").into_boxed_path(), dir.path().join("b").into_boxed_path()]);
+    // Hardlinks haven't been compared with anything, so they're not duplicates
+    assert_eq!(0, d.dupe_paths().count());
+}
+
+#[test]
+fn resume_checkpoint() {
+    let dir = TempDir::new("checkpointtest").unwrap();
+    fs::create_dir(dir.path().join("sub")).unwrap();
+    fs::write(dir.path().join("a"), "foo").unwrap();
+    fs::write(dir.path().join("sub/b"), "foo").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.run_mode = RunMode::DryRun;
+    d.enqueue(dir.path()).unwrap();
+    let checkpoint_path = dir.path().join("checkpoint");
+    d.checkpoint().save(&checkpoint_path).unwrap();
+    let checkpoint = Checkpoint::load(&checkpoint_path).unwrap();
+    fs::remove_file(&checkpoint_path).unwrap();
+    assert_eq!(checkpoint.dirs.len(), 1);
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.run_mode = RunMode::DryRun;
+    d.resume(checkpoint).unwrap();
+    // Already in the checkpoint
+    d.enqueue(dir.path()).unwrap();
+    d.flush().unwrap();
+    assert_eq!(d.roots().len(), 1);
+    assert_eq!(1, d.dupe_paths().count());
+}
+
+#[test]
+fn checkpoint_absolute_paths() {
+    let dir = TempDir::new("absolutetest").unwrap();
+    fs::write(dir.path().join("a"), "foo").unwrap();
+    // Same directory, relative to the working directory
+    let depth = fs::canonicalize(std::env::current_dir().unwrap()).unwrap().components().count() - 1;
+    let relative = std::iter::repeat("..").take(depth).collect::<std::path::PathBuf>().join(dir.path().strip_prefix("/").unwrap());
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.run_mode = RunMode::DryRun;
+    d.scan(&relative).unwrap();
+    assert_eq!(d.roots(), [relative.into_boxed_path()]);
+    let checkpoint = d.checkpoint();
+    assert!(checkpoint.roots.iter().chain(&checkpoint.files).all(|path| path.is_absolute()));
+    assert!(checkpoint.files.iter().all(|path| path.exists()));
+}
+
+#[test]
+fn isolate_roots() {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = TempDir::new("isolatetest").unwrap();
+    let a = dir.path().join("a");
+    let b = dir.path().join("b");
+    fs::create_dir(&a).unwrap();
+    fs::create_dir(&b).unwrap();
+    for path in [a.join("1"), a.join("2"), b.join("1"), b.join("2"), b.join("3")] {
+        fs::write(path, "foo").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.isolate_roots = true;
+    d.enqueue(&a).unwrap();
+    d.enqueue(&b).unwrap();
+    d.flush().unwrap();
+
+    let ino = |path: std::path::PathBuf| fs::metadata(path).unwrap().ino();
+    let a_inodes = [ino(a.join("1")), ino(a.join("2"))];
+    let b_inodes = [ino(b.join("1")), ino(b.join("2")), ino(b.join("3"))];
+    assert_ne!(a_inodes[0], a_inodes[1]);
+    assert_ne!(b_inodes[0], b_inodes[1]);
+    assert_ne!(b_inodes[1], b_inodes[2]);
+    assert_ne!(b_inodes[0], b_inodes[2]);
+    // Two of the files in b are linked to files in a
+    assert_eq!(2, b_inodes.iter().filter(|ino| a_inodes.contains(ino)).count());
+}
+
+#[test]
+fn reference_and_protect() {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = TempDir::new("referencetest").unwrap();
+    let master = dir.path().join("master");
+    let staging = dir.path().join("staging");
+    fs::create_dir(&master).unwrap();
+    fs::create_dir(&staging).unwrap();
+    fs::write(master.join("a"), "foo").unwrap();
+    fs::write(staging.join("a"), "foo").unwrap();
+    fs::write(staging.join("b"), "foo").unwrap();
+    fs::write(staging.join("keep.txt"), "foo").unwrap();
+
+    let mut protect = ExcludeRules::new();
+    protect.add_line("*.txt");
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.protect_rules(protect);
+    d.enqueue(&staging).unwrap();
+    d.enqueue_reference(&master).unwrap();
+    d.flush().unwrap();
+
+    let ino = |path: std::path::PathBuf| fs::metadata(path).unwrap().ino();
+    let master_ino = ino(master.join("a"));
+    assert_eq!(master_ino, ino(staging.join("a")));
+    assert_eq!(master_ino, ino(staging.join("b")));
+    assert_ne!(master_ino, ino(staging.join("keep.txt")));
+}
+
+#[test]
+fn preferred_root() {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = TempDir::new("prefertest").unwrap();
+    let archive = dir.path().join("archive");
+    let staging = dir.path().join("staging");
+    fs::create_dir(&archive).unwrap();
+    fs::create_dir(&staging).unwrap();
+    fs::write(archive.join("a"), "foo").unwrap();
+    // More links would normally make it the source
+    fs::write(staging.join("a"), "foo").unwrap();
+    fs::hard_link(staging.join("a"), staging.join("b")).unwrap();
+
+    let archive_ino = fs::metadata(archive.join("a")).unwrap().ino();
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.enqueue(&staging).unwrap();
+    d.enqueue_preferred(&archive).unwrap();
+    d.flush().unwrap();
+
+    assert_eq!(archive_ino, fs::metadata(archive.join("a")).unwrap().ino());
+    assert_eq!(archive_ino, fs::metadata(staging.join("a")).unwrap().ino());
+    assert_eq!(archive_ino, fs::metadata(staging.join("b")).unwrap().ino());
+}
+
+#[test]
+fn keep_matching() {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = TempDir::new("keepmatchingtest").unwrap();
+    fs::create_dir(dir.path().join("originals")).unwrap();
+    let original = dir.path().join("originals/a");
+    fs::write(&original, "foo").unwrap();
+    fs::write(dir.path().join("b"), "foo").unwrap();
+    // More links would normally make it the source
+    fs::hard_link(dir.path().join("b"), dir.path().join("c")).unwrap();
+
+    let mut keep = ExcludeRules::new();
+    keep.add_line("originals/*");
+    let original_ino = fs::metadata(&original).unwrap().ino();
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.keep_matching(keep);
+    d.scan(dir.path()).unwrap();
+
+    assert_eq!(original_ino, fs::metadata(&original).unwrap().ino());
+    assert_eq!(original_ino, fs::metadata(dir.path().join("b")).unwrap().ino());
+}
+
+#[test]
+fn keep_oldest() {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = TempDir::new("oldesttest").unwrap();
+    let (old, new) = (dir.path().join("old"), dir.path().join("new"));
+    fs::write(&old, "foo").unwrap();
+    fs::write(&new, "foo").unwrap();
+    // More links would normally make it the source
+    fs::hard_link(&new, dir.path().join("new2")).unwrap();
+    let old_c = std::ffi::CString::new(old.as_os_str().as_bytes()).unwrap();
+    let times = [libc::timeval { tv_sec: 1_000_000_000, tv_usec: 0 }; 2];
+    assert_eq!(0, unsafe { libc::utimes(old_c.as_ptr(), times.as_ptr()) });
+
+    let old_ino = fs::metadata(&old).unwrap().ino();
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.keep = Keep::Oldest;
+    d.scan(dir.path()).unwrap();
+
+    assert_eq!(old_ino, fs::metadata(&new).unwrap().ino());
+    assert_eq!(1_000_000_000, fs::metadata(&new).unwrap().mtime());
+}
+
+#[test]
+fn linked_mtime() {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = TempDir::new("linkedmtimetest").unwrap();
+    let (old, new) = (dir.path().join("old"), dir.path().join("new"));
+    fs::write(&old, "foo").unwrap();
+    fs::write(&new, "foo").unwrap();
+    // More links make it the source
+    fs::hard_link(&new, dir.path().join("new2")).unwrap();
+    let old_c = std::ffi::CString::new(old.as_os_str().as_bytes()).unwrap();
+    let times = [libc::timeval { tv_sec: 1_000_000_000, tv_usec: 0 }; 2];
+    assert_eq!(0, unsafe { libc::utimes(old_c.as_ptr(), times.as_ptr()) });
+
+    let new_ino = fs::metadata(&new).unwrap().ino();
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.linked_mtime = LinkedMtime::Oldest;
+    d.scan(dir.path()).unwrap();
+
+    assert_eq!(new_ino, fs::metadata(&old).unwrap().ino());
+    assert_eq!(1_000_000_000, fs::metadata(&new).unwrap().mtime());
+}
+
+#[test]
+fn export_import_hashes() {
+    let dir = TempDir::new("manifesttest").unwrap();
+    let manifest_dir = TempDir::new("manifesttest2").unwrap();
+    let a_path = dir.path().join("a");
+    fs::write(&a_path, "foo").unwrap();
+    fs::write(dir.path().join("b"), "bar").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.run_mode = RunMode::DryRunNoMerging;
+    d.scan(dir.path()).unwrap();
+    let mut exported = Vec::new();
+    d.export_hashes(&mut exported).unwrap();
+    let exported = String::from_utf8(exported).unwrap();
+    assert_eq!(2, exported.lines().filter(|line| !line.starts_with('#')).count());
+
+    // As if the file was on another machine
+    let manifest_path = manifest_dir.path().join("hashes.b3");
+    fs::write(&manifest_path, exported.replace(&*a_path.to_string_lossy(), "/nonexistent/a")).unwrap();
+
+    let log_path = manifest_dir.path().join("log");
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.run_mode = RunMode::DryRunNoMerging;
+    d.import_hashes(HashManifest::from_file(&manifest_path).unwrap());
+    d.set_listener(Box::new(LogFile::create(&log_path).unwrap()));
+    d.scan(dir.path()).unwrap();
+    let log = fs::read_to_string(&log_path).unwrap();
+    let found: Vec<_> = log.lines().filter_map(|line| line.split_once("\tmanifest-dupe\t")).map(|(_, paths)| paths).collect();
+    assert_eq!(vec![format!("/nonexistent/a\t{}", a_path.display())], found);
+}
+
+#[test]
+fn out_of_date_imported_hashes() {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = TempDir::new("manifestverifytest").unwrap();
+    let manifest_dir = TempDir::new("manifestverifytest2").unwrap();
+    let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+    fs::write(&a, "foo").unwrap();
+    fs::write(&b, "bar").unwrap();
+    // As if b had been changed without changing its size and modification time
+    let hash = blake3::hash(b"foo").to_hex();
+    let mut manifest = String::new();
+    for path in [&a, &b] {
+        let m = fs::metadata(path).unwrap();
+        manifest += &format!("# {} {} {}\n{}  {}\n", m.size(), m.mtime(), m.mtime_nsec(), hash, path.display());
+    }
+    let manifest_path = manifest_dir.path().join("hashes.b3");
+    fs::write(&manifest_path, manifest).unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.run_mode = RunMode::Hardlink;
+    d.import_hashes(HashManifest::from_file(&manifest_path).unwrap());
+    assert!(d.settings.verify_before_link);
+    d.scan(dir.path()).unwrap();
+    assert_ne!(fs::metadata(&a).unwrap().ino(), fs::metadata(&b).unwrap().ino());
+    assert_eq!("bar", fs::read_to_string(&b).unwrap());
+}
+
+#[test]
+fn add_files() {
+    let dir = TempDir::new("addfilestest").unwrap();
+    for name in ["a", "b", "c"] {
+        fs::write(dir.path().join(name), "same").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.run_mode = RunMode::DryRunNoMerging;
+    d.add_files(vec![dir.path().join("a"), dir.path().join("b"), dir.path().to_path_buf()]);
+    d.flush().unwrap();
+    let dupes = d.dupes();
+    assert_eq!(1, dupes.len());
+    assert_eq!(2, dupes[0].len());
+    assert_eq!(2, d.stats().added);
+    assert_eq!(1, d.stats().dupes);
+    assert_eq!(1, d.stats().skipped);
+    assert_eq!((1, 4, 8), (d.stats().unique_files(), d.stats().unique_bytes(), d.stats().bytes_added));
+    assert_eq!(2., d.stats().dedupe_ratio());
+    assert_eq!((8, 8), (d.stats().bytes_compared, d.stats().bytes_read));
+}
+
+#[test]
+fn trash_errors_not_fatal() {
+    let dir = TempDir::new("trashtest").unwrap();
+    let quarantine = TempDir::new("trashtest2").unwrap();
+    let path = |name: &str| dir.path().join(name);
+    for sub in ["x", "y", "z"] {
+        fs::create_dir(path(sub)).unwrap();
+    }
+    for name in ["x/a", "y/b", "z/c"] {
+        fs::write(path(name), "same").unwrap();
+    }
+    // Has most links, so it's kept
+    fs::hard_link(path("x/a"), path("x/a2")).unwrap();
+    // y/b can't be moved into a directory that is a file
+    fs::write(quarantine.path().join("y"), "").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.run_mode = RunMode::Trash;
+    d.settings.trash_dir = Some(quarantine.path().to_path_buf());
+    d.add_root(dir.path()).unwrap();
+    d.add_files(["x/a", "x/a2", "y/b", "z/c"].into_iter().map(path));
+    d.flush().unwrap();
+    assert!(path("y/b").exists());
+    assert!(!path("z/c").exists());
+    assert!(quarantine.path().join("z/c").exists());
+}
+
+#[test]
+fn add_group() {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = TempDir::new("addgrouptest").unwrap();
+    let path = |name: &str| dir.path().join(name);
+    for name in ["a1", "a2", "b1", "b2", "kept"] {
+        fs::write(path(name), "same").unwrap();
+    }
+    let ino = |name: &str| fs::metadata(path(name)).unwrap().ino();
+    let kept_ino = ino("kept");
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.run_mode = RunMode::Hardlink;
+    let mut protect = ExcludeRules::new();
+    protect.add_line("/kept");
+    d.protect_rules(protect);
+    d.add_root(dir.path()).unwrap();
+    d.add_group(vec![path("a1"), path("a2")]);
+    d.add_group(vec![path("b1"), path("b2"), path("kept")]);
+    d.flush().unwrap();
+    assert_eq!(ino("a1"), ino("a2"));
+    assert_ne!(ino("a1"), ino("b1"));
+    // Protect rules are relative to the root
+    assert_eq!(kept_ino, ino("kept"));
+    assert_eq!((kept_ino, kept_ino), (ino("b1"), ino("b2")));
+}
+
+#[test]
+fn outside_roots_not_replaced() {
+    use std::os::unix::fs::MetadataExt;
+
+    let root = TempDir::new("rootstest").unwrap();
+    let outside = TempDir::new("outsidetest").unwrap();
+    fs::write(root.path().join("a"), "same").unwrap();
+    fs::hard_link(root.path().join("a"), root.path().join("b")).unwrap();
+    let outside_file = outside.path().join("c");
+    fs::write(&outside_file, "same").unwrap();
+    let ino = fs::metadata(&outside_file).unwrap().ino();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.scan(root.path()).unwrap();
+    d.add_files(vec![outside_file.clone()]);
+    d.flush().unwrap();
+    assert_eq!(ino, fs::metadata(&outside_file).unwrap().ino());
+}
+
+#[test]
+fn before_link_veto() {
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+
+    #[derive(Debug)]
+    struct Veto;
+    impl ScanListener for Veto {
+        fn file_scanned(&mut self, _: &Path, _: &Stats) {}
+        fn scan_over(&self, _: &Scanner, _: &Stats, _: Duration) {}
+        fn hardlinked(&mut self, _: &Path, _: &Path) {}
+        fn duplicate_found(&mut self, _: &Path, _: &Path) {}
+        fn before_link(&mut self, _: &Path, dst: &Path) -> Decision {
+            if dst.ends_with("keep-me") { Decision::Skip } else { Decision::Proceed }
+        }
+    }
+
+    let dir = TempDir::new("vetotest").unwrap();
+    for name in ["a", "b", "keep-me"] {
+        fs::write(dir.path().join(name), "same").unwrap();
+    }
+    // Makes "a" the one that is kept
+    fs::hard_link(dir.path().join("a"), dir.path().join("a2")).unwrap();
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.set_listener(Box::new(Veto));
+    d.scan(dir.path()).unwrap();
+    let ino = |name| fs::metadata(dir.path().join(name)).unwrap().ino();
+    assert_eq!(ino("a"), ino("b"));
+    assert_ne!(ino("a"), ino("keep-me"));
+}
+
+#[test]
+fn phases() {
+    use std::cell::RefCell;
+    use std::path::Path;
+    use std::rc::Rc;
+
+    #[derive(Debug, Default)]
+    struct Phases {
+        started: Vec<Phase>,
+        last_progress: Option<(Phase, usize, usize)>,
+    }
+    #[derive(Debug)]
+    struct PhaseListener(Rc<RefCell<Phases>>);
+    impl ScanListener for PhaseListener {
+        fn file_scanned(&mut self, _: &Path, _: &Stats) {}
+        fn scan_over(&self, _: &Scanner, _: &Stats, _: Duration) {}
+        fn hardlinked(&mut self, _: &Path, _: &Path) {}
+        fn duplicate_found(&mut self, _: &Path, _: &Path) {}
+        fn phase_started(&mut self, phase: Phase) {
+            self.0.borrow_mut().started.push(phase);
+        }
+        fn phase_progress(&mut self, phase: Phase, done: usize, total: usize) {
+            assert!(done <= total);
+            if phase == Phase::Scanning {
+                self.0.borrow_mut().last_progress = Some((phase, done, total));
+            }
+        }
+    }
+
+    let dir = TempDir::new("phasestest").unwrap();
+    fs::create_dir(dir.path().join("sub")).unwrap();
+    fs::write(dir.path().join("a"), "same").unwrap();
+    fs::write(dir.path().join("sub/b"), "same").unwrap();
+    let phases = Rc::new(RefCell::new(Phases::default()));
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.count_first = true;
+    d.set_listener(Box::new(PhaseListener(Rc::clone(&phases))));
+    d.scan(dir.path()).unwrap();
+    let phases = phases.borrow();
+    assert_eq!(vec![Phase::Counting, Phase::Scanning, Phase::LinkingDeferred], phases.started);
+    assert_eq!(Some((Phase::Scanning, 2, 2)), phases.last_progress);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn skip_open_files() {
+    let dir = TempDir::new("openfilestest").unwrap();
+    for name in ["a", "b", "c"] {
+        fs::write(dir.path().join(name), "same").unwrap();
+    }
+    let _writer = fs::OpenOptions::new().append(true).open(dir.path().join("c")).unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.skip_open_files = true;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(2, d.stats().added);
+    assert_eq!(1, d.stats().skipped_by_reason.open_for_writing);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn require_same_xattrs() {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = TempDir::new("xattrtest").unwrap();
+    for name in ["a", "b", "c"] {
+        fs::write(dir.path().join(name), "same").unwrap();
+    }
+    let c = dir.path().join("c");
+    let c_path = std::ffi::CString::new(c.as_os_str().as_bytes()).unwrap();
+    let value = b"1234";
+    if unsafe { libc::setxattr(c_path.as_ptr(), b"user.checksum\0".as_ptr().cast(), value.as_ptr().cast(), value.len(), 0) } != 0 {
+        return; // Not supported by the filesystem
+    }
+    let c_ino = fs::metadata(&c).unwrap().ino();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.require_same_xattrs = true;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(c_ino, fs::metadata(&c).unwrap().ino());
+    assert_ne!(c_ino, fs::metadata(dir.path().join("a")).unwrap().ino());
+}
+
+#[test]
+fn access_conflicts() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = TempDir::new("accesstest").unwrap();
+    for name in ["a", "b", "c"] {
+        fs::write(dir.path().join(name), "same").unwrap();
+        fs::set_permissions(dir.path().join(name), fs::Permissions::from_mode(0o644)).unwrap();
+    }
+    fs::set_permissions(dir.path().join("c"), fs::Permissions::from_mode(0o600)).unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.run_mode = RunMode::DryRun;
+    d.scan(dir.path()).unwrap();
+    let conflicts = d.access_conflicts();
+    assert_eq!(1, conflicts.len());
+    assert_eq!(3, conflicts[0].files.len());
+    assert!(conflicts[0].mode);
+    assert!(!conflicts[0].owner);
+    assert!(conflicts[0].files.iter().any(|f| f.mode == 0o600));
+}
+
+#[test]
+fn custom_filter() {
+    let dir = TempDir::new("filtertest").unwrap();
+    fs::create_dir(dir.path().join("skip")).unwrap();
+    for name in ["a", "b", "c.keep", "skip/d"] {
+        fs::write(dir.path().join(name), "same").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.run_mode = RunMode::DryRunNoMerging;
+    d.settings.filter = Some(FileFilter::new(|path, metadata| {
+        path.file_name().map_or(true, |name| name != "skip" && name != "a") && (metadata.is_dir() || metadata.len() > 0)
+    }));
+    d.scan(dir.path()).unwrap();
+    let dupes = d.dupes();
+    assert_eq!(1, dupes.len());
+    let mut names: Vec<_> = dupes[0].iter().map(|set| set.paths[0].file_name().unwrap().to_owned()).collect();
+    names.sort();
+    assert_eq!(vec!["b", "c.keep"], names);
+}
+
+#[test]
+fn empty_files() {
+    let dir = TempDir::new("emptytest").unwrap();
+    fs::write(dir.path().join("a"), "").unwrap();
+    fs::write(dir.path().join("b"), "").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.run_mode = RunMode::DryRunNoMerging;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(0, d.dupes().len());
+
+    let mut d = Scanner::new();
+    d.settings.run_mode = RunMode::DryRunNoMerging;
+    d.settings.include_empty = true;
+    d.scan(dir.path()).unwrap();
+    let dupes = d.dupes();
+    assert_eq!(1, dupes.len());
+    assert_eq!(2, dupes[0].len());
+}